@@ -0,0 +1,98 @@
+//! Times `annotate` on synthetic chapters, and a whole book with and without `parallel`.
+//! Run with `cargo bench`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use mdbook::book::{Book, BookItem, Chapter};
+use mdbook::Config;
+use mdbook_classy::{annotate, Classy};
+
+/// Plain paragraphs with no annotation anywhere, which never reach the parser.
+fn unannotated(paragraphs: usize) -> String {
+    (0..paragraphs)
+        .map(|i| format!("Paragraph {} has *some* `inline` markup.\n\n", i))
+        .collect()
+}
+
+/// Every other paragraph annotated, none nested.
+fn shallow(paragraphs: usize) -> String {
+    (0..paragraphs)
+        .map(|i| {
+            if i % 2 == 0 {
+                format!("{{:.note #p{}}}\nParagraph {} is wrapped.\n\n", i, i)
+            } else {
+                format!("Paragraph {} is not.\n\n", i)
+            }
+        })
+        .collect()
+}
+
+/// Annotated paragraphs in blockquotes nested `depth` deep, repeated `times`.
+fn nested(depth: usize, times: usize) -> String {
+    let mut chapter = String::new();
+    for _ in 0..times {
+        for level in 1..=depth {
+            let quote = "> ".repeat(level);
+            chapter.push_str(&format!(
+                "{q}{{:.level-{l}}}\n{q}Level {l}.\n{q}\n",
+                q = quote,
+                l = level
+            ));
+        }
+        chapter.push('\n');
+    }
+    chapter
+}
+
+/// Lots of one-line annotated blocks.
+fn many_small(blocks: usize) -> String {
+    (0..blocks).map(|_| "{:.a}\nx\n\n").collect()
+}
+
+fn chapters(c: &mut Criterion) {
+    let mut group = c.benchmark_group("annotate");
+    let cases = [
+        ("unannotated", unannotated(1000)),
+        ("shallow", shallow(1000)),
+        ("nested", nested(10, 50)),
+        ("many_small", many_small(5000)),
+    ];
+    for (name, chapter) in &cases {
+        group.throughput(Throughput::Bytes(chapter.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), chapter, |b, chapter| {
+            b.iter(|| annotate(black_box(chapter)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn book(c: &mut Criterion) {
+    let mut book = Book::new();
+    for i in 0..100 {
+        let chapter = Chapter::new(
+            &format!("Chapter {}", i),
+            shallow(200),
+            format!("chapter_{}.md", i),
+            vec![],
+        );
+        book.push_item(BookItem::Chapter(chapter));
+    }
+
+    let mut group = c.benchmark_group("book");
+    for parallel in &[false, true] {
+        let config: Config = format!("[preprocessor.classy]\nparallel = {}\n", parallel)
+            .parse()
+            .unwrap();
+        let id = BenchmarkId::new("parallel", parallel);
+        group.bench_with_input(id, &config, |b, config| {
+            b.iter_batched_ref(
+                || book.clone(),
+                |book| Classy::new().annotate_book(config, book).unwrap(),
+                criterion::BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, chapters, book);
+criterion_main!(benches);
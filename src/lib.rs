@@ -0,0 +1,4205 @@
+use log::{debug, info, warn};
+use mdbook::book::{Book, BookItem};
+use mdbook::errors::Error;
+use mdbook::preprocess::{Preprocessor, PreprocessorContext};
+use mdbook::Config as BookConfig;
+use pulldown_cmark::{CodeBlockKind, CowStr, Event, Parser, Tag};
+use pulldown_cmark_to_cmark::State;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::ops::Range;
+
+/// Renderers whose output can carry the raw html divs classy emits.
+const SUPPORTED_RENDERERS: &[&str] = &["html", "epub"];
+
+#[derive(Default)]
+pub struct Classy;
+
+impl Classy {
+    pub fn new() -> Classy {
+        Classy
+    }
+
+    /// Annotate every chapter of `book` as `config`'s `[preprocessor.classy]` table says.
+    /// In strict mode problems from all chapters fail it together, one per line; otherwise they are logged
+    /// as warnings and the malformed annotations stay in the text.
+    pub fn annotate_book(&self, config: &BookConfig, book: &mut Book) -> Result<(), Error> {
+        let options = Options::new(Config::from_book(config)?)?;
+        let (problems, _) = self.annotate_chapters(&options, book)?;
+        report(&options, problems)
+    }
+
+    /// Like `annotate_book`, but fail on any problem whatever the mode: for linting.
+    pub fn check_book(&self, config: &BookConfig, book: &mut Book) -> Result<(), Error> {
+        let options = Options::new(Config::from_book(config)?)?;
+        let (problems, _) = self.annotate_chapters(&options, book)?;
+        if !problems.is_empty() {
+            return Err(Error::msg(problems.join("\n")));
+        }
+        Ok(())
+    }
+
+    /// Every class `book`'s annotations give blocks, as written out (prefixed), sorted, with how many blocks
+    /// have it: for checking a theme's css covers them.  Problems are reported like `annotate_book` does.
+    pub fn list_classes(
+        &self,
+        config: &BookConfig,
+        book: &mut Book,
+    ) -> Result<Vec<(String, usize)>, Error> {
+        let options = Options::new(Config::from_book(config)?)?;
+        let (problems, counts) = self.annotate_chapters(&options, book)?;
+        report(&options, problems)?;
+        let mut classes: BTreeMap<String, usize> = BTreeMap::new();
+        for (_, counts) in counts {
+            for (class, count) in counts.classes {
+                *classes.entry(class).or_insert(0) += count;
+            }
+        }
+        Ok(classes.into_iter().collect())
+    }
+
+    /// Whether classy should run for `renderer` in the book `config` belongs to, as its `supported-renderers`
+    /// says.  A `[preprocessor.classy]` table that can't be read leaves it to the defaults.
+    pub fn supports_renderer_in(&self, config: &BookConfig, renderer: &str) -> bool {
+        match Config::from_book(config) {
+            Ok(config) => config.supports_renderer(renderer),
+            Err(_) => self.supports_renderer(renderer),
+        }
+    }
+
+    /// Annotate every chapter of `book`, returning the problems found in book order, and what was done to
+    /// each chapter.  Fails if a chapter has more blocks than `max-blocks` allows.
+    fn annotate_chapters(&self, options: &Options, book: &mut Book) -> Result<Annotated, Error> {
+        let mut chapters = vec![];
+        collect_chapters(&mut book.sections, 1, options.process_drafts, &mut chapters);
+        chapters.retain(|(location, _, _)| {
+            let excluded = options.excludes(location);
+            if excluded {
+                debug!("{}: excluded, leaving it as it is", location);
+            }
+            !excluded
+        });
+        info!("Annotating {} chapter(s)", chapters.len());
+        let annotate_chapter = |(location, level, content): (String, usize, &mut String)| {
+            let annotated = classy(content, &location, level, options);
+            (location, annotated)
+        };
+        // Chapters don't share anything, so each can be transformed on its own thread.  Collecting the
+        // results keeps the problems in book order either way.
+        let results: Vec<(String, Result<AnnotatedChapter, Error>)> = if options.parallel {
+            chapters.into_par_iter().map(annotate_chapter).collect()
+        } else {
+            chapters.into_iter().map(annotate_chapter).collect()
+        };
+        let mut problems = vec![];
+        let mut counts = vec![];
+        for (location, result) in results {
+            let (chapter_counts, chapter_problems) = result?;
+            counts.push((location, chapter_counts));
+            problems.extend(chapter_problems);
+        }
+        if options.stats {
+            eprintln!("{}", stats_summary(&counts));
+        }
+        Ok((problems, counts))
+    }
+}
+
+/// In strict mode, fail with all of a book's problems together, one per line; otherwise log them as warnings.
+fn report(options: &Options, problems: Vec<String>) -> Result<(), Error> {
+    if options.strict && !problems.is_empty() {
+        return Err(Error::msg(problems.join("\n")));
+    }
+    for problem in problems {
+        warn!("{}", problem);
+    }
+    Ok(())
+}
+
+impl Preprocessor for Classy {
+    fn name(&self) -> &str {
+        "classy"
+    }
+    fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book, Error> {
+        self.annotate_book(&ctx.config, &mut book)?;
+        Ok(book)
+    }
+    fn supports_renderer(&self, renderer: &str) -> bool {
+        Config::default().supports_renderer(renderer)
+    }
+}
+
+/// The alert kinds GitHub renders, lowercased as they appear in the emitted class.
+const GITHUB_ALERTS: &[&str] = &["note", "tip", "important", "warning", "caution"];
+
+/// The `[preprocessor.classy]` table of book.toml, as written.  Every setting is optional; the README
+/// describes what each does.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Config {
+    /// Admonition presets by class name, `title` and `role` for each.
+    pub admonitions: Option<toml::Value>,
+    pub github_alerts: bool,
+    pub aria: bool,
+    pub parallel: bool,
+    pub stats: bool,
+    pub class_prefix: String,
+    pub strict: bool,
+    pub any_class_names: bool,
+    pub forbid_inline_styles: bool,
+    pub allowed_classes: Option<Vec<String>>,
+    /// The space-separated classes each alias stands for.
+    pub aliases: HashMap<String, String>,
+    pub open_token: Option<String>,
+    pub close_token: Option<String>,
+    pub fenced_divs: bool,
+    pub merge_adjacent: bool,
+    pub ignore_near_misses: bool,
+    pub trailing_markers: bool,
+    pub block_anchors: bool,
+    pub process_drafts: bool,
+    /// The most blocks a chapter may annotate, if not 10000.
+    pub max_blocks: Option<usize>,
+    pub debug_markers: bool,
+    pub inherit_parent_class: bool,
+    pub emit_depth_attr: bool,
+    /// What `data-depth` counts: `"block"` (the default) for annotated blocks, or `"list"` for lists.
+    pub depth_source: Option<String>,
+    /// Classes to wrap every chapter in, space-separated.
+    pub chapter_class: Option<String>,
+    pub chapter_level_class: bool,
+    pub default_tag: Option<String>,
+    /// Classes every annotated block gets after its own.
+    pub default_classes: Vec<String>,
+    /// Chapters to leave alone, by their path in the book's source directory, or whole directories ending
+    /// in `/`.
+    pub exclude: Vec<String>,
+    /// The html to open and close blocks of each class with, instead of the usual tags.
+    pub templates: HashMap<String, Template>,
+    /// The renderers to run for, if not just html and epub.
+    pub supported_renderers: Option<Renderers>,
+    /// Keys that aren't classy settings, kept to be warned about.
+    #[serde(flatten, skip_serializing)]
+    unknown: HashMap<String, toml::Value>,
+}
+
+/// What a block of a templated class is opened and closed with.  `{class}`, `{id}` and `{title}` in either
+/// are replaced by the block's classes, id and title, html-escaped (and empty if it has none).
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Template {
+    pub open: String,
+    pub close: String,
+}
+
+/// The `supported-renderers` setting: `true` for any renderer, or a list of renderer names where `"*"` stands
+/// for any.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Renderers {
+    All(bool),
+    Only(Vec<String>),
+}
+
+/// Keys mdbook itself reads from every preprocessor's table.
+const MDBOOK_KEYS: &[&str] = &["command", "renderer", "before", "after"];
+
+impl Config {
+    /// Read the `[preprocessor.classy]` table of a book's configuration.  A book without one gets the defaults.
+    pub fn from_book(config: &BookConfig) -> Result<Config, Error> {
+        match config.get_preprocessor("classy") {
+            Some(table) => Config::from_table(table),
+            None => Ok(Config::default()),
+        }
+    }
+
+    /// These settings, defaults included, as the `[preprocessor.classy]` table of a book.toml.
+    pub fn to_toml(&self) -> Result<String, Error> {
+        let mut classy_table = toml::value::Table::new();
+        classy_table.insert("classy".to_string(), toml::Value::try_from(self)?);
+        let mut preprocessor = toml::value::Table::new();
+        preprocessor.insert("preprocessor".to_string(), toml::Value::Table(classy_table));
+        Ok(toml::to_string(&toml::Value::Table(preprocessor))?)
+    }
+
+    /// Whether classy should run for `renderer`.  Only html and epub can carry the raw html it emits, unless
+    /// `supported-renderers` vouches for others.
+    pub fn supports_renderer(&self, renderer: &str) -> bool {
+        match &self.supported_renderers {
+            Some(Renderers::All(all)) => *all || SUPPORTED_RENDERERS.contains(&renderer),
+            Some(Renderers::Only(renderers)) => renderers.iter().any(|r| r == "*" || r == renderer),
+            None => SUPPORTED_RENDERERS.contains(&renderer),
+        }
+    }
+
+    /// Read a `[preprocessor.classy]` table, warning about any key that isn't a setting: it's most likely a typo.
+    fn from_table(table: &toml::value::Table) -> Result<Config, Error> {
+        let config: Config = toml::Value::Table(table.clone())
+            .try_into()
+            .map_err(|e| Error::msg(format!("invalid [preprocessor.classy] settings: {}", e)))?;
+        let mut unknown: Vec<&String> = config
+            .unknown
+            .keys()
+            .filter(|key| !MDBOOK_KEYS.contains(&key.as_str()))
+            .collect();
+        unknown.sort();
+        for key in unknown {
+            warn!(
+                "preprocessor.classy.{} isn't a classy setting, ignoring it",
+                key
+            );
+        }
+        Ok(config)
+    }
+}
+
+/// Settings read from the `[preprocessor.classy]` table of book.toml, checked and ready to use.
+#[derive(Debug, Default)]
+struct Options {
+    /// Admonition presets by class name, only in effect when `[preprocessor.classy.admonitions]` exists.
+    admonitions: Option<HashMap<String, Admonition>>,
+    /// Whether to turn GitHub-style `> [!NOTE]` blockquotes into alert blocks.
+    github_alerts: bool,
+    /// Whether admonitions and alerts get a `role` and `aria-label` for screen readers.
+    aria: bool,
+    /// Whether chapters are transformed in parallel.
+    parallel: bool,
+    /// Whether to print a summary of what was annotated once the book is done.
+    stats: bool,
+    /// Put in front of every class emitted, to keep them apart from the theme's.
+    class_prefix: String,
+    /// Whether malformed annotations fail the build rather than just being warned about.
+    strict: bool,
+    /// Whether class names may contain characters other than letters, digits, `-` and `_`.
+    any_class_names: bool,
+    /// Whether annotations may not set a `style`, for books served with a strict content security policy.
+    forbid_inline_styles: bool,
+    /// The only classes annotations may use, if restricted.
+    allowed_classes: Option<Vec<String>>,
+    /// The classes each alias class adds, from `[preprocessor.classy.aliases]`.
+    aliases: HashMap<String, Vec<String>>,
+    /// Chapter paths and directories (ending in `/`) not to annotate.
+    exclude: Vec<String>,
+    /// Html to open and close blocks with by class, from `[preprocessor.classy.templates]`.
+    templates: HashMap<String, Template>,
+    /// The element annotated blocks are wrapped in when they don't say, if not `div`.
+    default_tag: Option<String>,
+    /// The classes every annotated block gets after its own.
+    default_classes: Vec<String>,
+    /// The classes every chapter is wrapped in, if any.
+    chapter_class: Option<String>,
+    /// Whether every chapter is wrapped in a `chapter-level-N` class for how deep it is in the book.
+    chapter_level_class: bool,
+    /// Whether annotated blocks get a `data-depth` saying how deeply they're nested.
+    emit_depth_attr: bool,
+    /// Whether that depth is how many lists a block is in, rather than how many annotated blocks.
+    list_depth: bool,
+    /// Whether annotated blocks inside annotated blocks get a class compounded from their parent's.
+    inherit_parent_class: bool,
+    /// Whether every annotated block's tags are followed by a comment naming the annotation.
+    debug_markers: bool,
+    /// What an annotation starts with, if not `{:`.
+    open_token: Option<String>,
+    /// What an annotation ends with, if not `}`.
+    close_token: Option<String>,
+    /// Whether `:::class` and `:::` lines open and close blocks too.
+    fenced_divs: bool,
+    /// Whether annotated paragraphs that follow each other directly share a single wrapper.
+    merge_adjacent: bool,
+    /// Whether paragraphs starting with what is nearly an annotation, like `{.note}`, go without a warning.
+    ignore_near_misses: bool,
+    /// Whether an annotation can also be a paragraph's last line, kramdown-style.
+    trailing_markers: bool,
+    /// Whether blocks with an id start with a `#` link to themselves.
+    block_anchors: bool,
+    /// Whether draft chapters, which have no file, are annotated too.
+    process_drafts: bool,
+    /// The most blocks a chapter may annotate before it fails the build, if not `DEFAULT_MAX_BLOCKS`.
+    max_blocks: Option<usize>,
+}
+
+/// A class that, when annotated, turns its block into a titled admonition.
+#[derive(Debug)]
+struct Admonition {
+    title: String,
+    /// The ARIA role given to the block when `aria` is on.
+    role: String,
+}
+
+/// The ARIA role that suits an admonition or alert of the given class: warnings interrupt, the rest don't.
+fn default_role(class: &str) -> &'static str {
+    match class {
+        "warning" | "danger" | "caution" => "alert",
+        _ => "note",
+    }
+}
+
+/// How many blocks a chapter may annotate unless `max-blocks` says otherwise: far more than any written by
+/// hand, so only generated chapters run into it.
+const DEFAULT_MAX_BLOCKS: usize = 10_000;
+
+/// What a collapsed `<details>` block says when the annotation gives no `summary`.
+const DEFAULT_SUMMARY: &str = "Details";
+
+/// The presets every `[preprocessor.classy.admonitions]` table starts from.
+const DEFAULT_ADMONITIONS: &[&str] = &["note", "warning", "tip", "danger"];
+
+impl Options {
+    /// Check the settings that take more than the right type to be valid, and resolve the rest.
+    fn new(config: Config) -> Result<Options, Error> {
+        if config.class_prefix.contains(char::is_whitespace) {
+            return Err(Error::msg(
+                "preprocessor.classy.class-prefix must be a string without spaces",
+            ));
+        }
+        for (key, token) in &[
+            ("open-token", &config.open_token),
+            ("close-token", &config.close_token),
+        ] {
+            if matches!(token, Some(token) if token.trim().is_empty()) {
+                return Err(Error::msg(format!(
+                    "preprocessor.classy.{} must be a non-empty string",
+                    key
+                )));
+            }
+        }
+        if config.max_blocks == Some(0) {
+            return Err(Error::msg(
+                "preprocessor.classy.max-blocks must be a positive number",
+            ));
+        }
+        let mut default_classes: Vec<String> = vec![];
+        for class in config
+            .default_classes
+            .iter()
+            .flat_map(|classes| classes.split_whitespace())
+        {
+            let valid = |c: char| c.is_alphanumeric() || c == '-' || c == '_';
+            if !config.any_class_names && !class.chars().all(valid) {
+                return Err(Error::msg(format!(
+                    "preprocessor.classy.default-classes has an invalid class name `{}`",
+                    class
+                )));
+            }
+            if let Some(allowed) = &config.allowed_classes {
+                if !allowed.iter().any(|allowed| allowed == class) {
+                    return Err(Error::msg(format!(
+                        "preprocessor.classy.default-classes has `{}`, which isn't an allowed class",
+                        class
+                    )));
+                }
+            }
+            if !default_classes.iter().any(|c| c == class) {
+                default_classes.push(class.to_string());
+            }
+        }
+        let list_depth = match config.depth_source.as_deref() {
+            None | Some("block") => false,
+            Some("list") => true,
+            Some(_) => {
+                return Err(Error::msg(
+                    "preprocessor.classy.depth-source must be \"block\" or \"list\"",
+                ))
+            }
+        };
+        if let Some(tag) = &config.default_tag {
+            if !is_tag_name(tag) {
+                return Err(Error::msg(format!(
+                    "preprocessor.classy.default-tag must be one of {}",
+                    BLOCK_TAGS.join(", ")
+                )));
+            }
+        }
+        let admonitions = match &config.admonitions {
+            Some(admonitions) => Some(parse_admonitions(admonitions)?),
+            None => None,
+        };
+        let aliases = config
+            .aliases
+            .into_iter()
+            .map(|(alias, classes)| {
+                let classes = classes.split_whitespace().map(String::from).collect();
+                (alias, classes)
+            })
+            .collect();
+        Ok(Options {
+            admonitions,
+            github_alerts: config.github_alerts,
+            aria: config.aria,
+            parallel: config.parallel,
+            stats: config.stats,
+            class_prefix: config.class_prefix,
+            strict: config.strict,
+            any_class_names: config.any_class_names,
+            forbid_inline_styles: config.forbid_inline_styles,
+            allowed_classes: config.allowed_classes,
+            aliases,
+            open_token: config.open_token,
+            close_token: config.close_token,
+            fenced_divs: config.fenced_divs,
+            merge_adjacent: config.merge_adjacent,
+            ignore_near_misses: config.ignore_near_misses,
+            trailing_markers: config.trailing_markers,
+            block_anchors: config.block_anchors,
+            process_drafts: config.process_drafts,
+            max_blocks: config.max_blocks,
+            debug_markers: config.debug_markers,
+            inherit_parent_class: config.inherit_parent_class,
+            emit_depth_attr: config.emit_depth_attr,
+            list_depth,
+            chapter_class: config.chapter_class,
+            chapter_level_class: config.chapter_level_class,
+            default_tag: config.default_tag,
+            default_classes,
+            templates: config.templates,
+            exclude: config.exclude,
+        })
+    }
+
+    fn open_token(&self) -> &str {
+        self.open_token.as_deref().unwrap_or("{:")
+    }
+
+    fn close_token(&self) -> &str {
+        self.close_token.as_deref().unwrap_or("}")
+    }
+
+    fn max_blocks(&self) -> usize {
+        self.max_blocks.unwrap_or(DEFAULT_MAX_BLOCKS)
+    }
+
+    /// Whether the chapter at `location` is excluded from annotation.
+    fn excludes(&self, location: &str) -> bool {
+        let location = location.replace('\\', "/");
+        self.exclude.iter().any(|excluded| {
+            location == *excluded
+                || (excluded.ends_with('/') && location.starts_with(excluded.as_str()))
+        })
+    }
+
+    /// A class as emitted, with the configured prefix.
+    fn prefixed(&self, class: &str) -> String {
+        format!("{}{}", self.class_prefix, class)
+    }
+}
+
+/// Merge the user's `[preprocessor.classy.admonitions]` table over the default presets.
+/// Each entry is a table, empty or with a `title` that replaces the capitalized class name and a `role`
+/// that replaces the default ARIA role.
+fn parse_admonitions(value: &toml::Value) -> Result<HashMap<String, Admonition>, Error> {
+    let table = value
+        .as_table()
+        .ok_or_else(|| Error::msg("preprocessor.classy.admonitions must be a table"))?;
+    let mut presets: HashMap<String, Admonition> = DEFAULT_ADMONITIONS
+        .iter()
+        .map(|class| {
+            let admonition = Admonition {
+                title: capitalize(class),
+                role: default_role(class).to_string(),
+            };
+            (class.to_string(), admonition)
+        })
+        .collect();
+    for (class, preset) in table {
+        let preset = preset.as_table().ok_or_else(|| {
+            Error::msg(format!(
+                "preprocessor.classy.admonitions.{} must be a table",
+                class
+            ))
+        })?;
+        let admonition = Admonition {
+            title: preset_string(preset, class, "title")?.unwrap_or_else(|| capitalize(class)),
+            role: preset_string(preset, class, "role")?
+                .unwrap_or_else(|| default_role(class).to_string()),
+        };
+        presets.insert(class.clone(), admonition);
+    }
+    Ok(presets)
+}
+
+fn preset_string(
+    preset: &toml::value::Table,
+    class: &str,
+    key: &str,
+) -> Result<Option<String>, Error> {
+    match preset.get(key) {
+        Some(value) => value.as_str().map(|s| Some(s.to_string())).ok_or_else(|| {
+            Error::msg(format!(
+                "preprocessor.classy.admonitions.{}.{} must be a string",
+                class, key
+            ))
+        }),
+        None => Ok(None),
+    }
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// The element, id, classes and other attributes requested by a single class annotation.
+#[derive(Debug, Default)]
+struct Attributes {
+    pub tag: Option<String>,
+    pub id: Option<String>,
+    pub classes: Vec<String>,
+    pub others: Vec<(String, String)>,
+    /// A caption shown as the first thing inside the block, from `title="..."`.
+    pub title: Option<String>,
+    /// The always-visible label of a collapsible `<details>` block, from `summary="..."`.
+    pub summary: Option<String>,
+    /// The `<figcaption>` a `<figure>` block ends with, from `caption="..."`.
+    pub caption: Option<String>,
+    /// Whether the annotation, alone in its paragraph, applies to the block after it, from `next`.
+    pub next: bool,
+}
+
+impl Attributes {
+    /// Parse the inside of a class annotation, e.g. `#intro.note .small big role="alert"`.
+    /// `.name` adds a class, `#name` sets the id, `key="value"` adds an attribute and bare words are
+    /// taken as classes.  The special `tag=name` pair swaps the wrapping `<div>` for another element, and
+    /// `title="..."` gives the block a caption.  `summary="..."` makes the block a collapsible `<details>`,
+    /// and `caption="..."` a `<figure>`.
+    /// The bare word `next` makes it apply to the following block (see `ClassAnnotator`).
+    /// Problems are described on their own, to be put after the annotation they are about.
+    fn parse(annotation: &str) -> Result<Attributes, String> {
+        let mut attributes = Attributes::default();
+        for token in split_tokens(annotation)? {
+            if token == "next" {
+                attributes.next = true;
+                continue;
+            }
+            if let Some(eq) = token.find('=') {
+                let (key, value) = parse_pair(&token[..eq], &token[eq + 1..])?;
+                match key {
+                    "tag" => attributes.set_tag(value)?,
+                    "title" => attributes.title = Some(value.to_string()),
+                    "summary" => attributes.summary = Some(value.to_string()),
+                    "caption" => attributes.caption = Some(value.to_string()),
+                    "id" => attributes.set_id(value)?,
+                    "style" => attributes.set_style(value)?,
+                    "class" => {
+                        for class in value.split_whitespace() {
+                            attributes.add_class(class);
+                        }
+                    }
+                    _ => attributes.others.push((key.to_string(), value.to_string())),
+                }
+                continue;
+            }
+            let mut sigil = '.';
+            let mut start = 0;
+            for (i, c) in token
+                .char_indices()
+                .chain(std::iter::once((token.len(), '.')))
+            {
+                if c != '.' && c != '#' {
+                    continue;
+                }
+                let name = &token[start..i];
+                if sigil == '#' {
+                    attributes.set_id(name)?;
+                } else if !name.is_empty() {
+                    attributes.add_class(name);
+                }
+                sigil = c;
+                start = i + c.len_utf8();
+            }
+        }
+        Ok(attributes)
+    }
+
+    /// Reject annotations the options don't allow, such as a class name with a typo like `foo;bar` in it.
+    fn validate(self, options: &Options) -> Result<Attributes, String> {
+        if !options.any_class_names {
+            let valid = |c: char| c.is_alphanumeric() || c == '-' || c == '_';
+            if let Some(class) = self.classes.iter().find(|class| !class.chars().all(valid)) {
+                return Err(format!("has an invalid class name `{}`", class));
+            }
+        }
+        if options.forbid_inline_styles && self.style().is_some() {
+            return Err("sets a style, but inline styles are forbidden in this book".to_string());
+        }
+        if let Some(allowed) = &options.allowed_classes {
+            if let Some(class) = self.classes.iter().find(|class| !allowed.contains(class)) {
+                return Err(format!("uses `{}`, which isn't an allowed class", class));
+            }
+        }
+        Ok(self)
+    }
+
+    /// Wrap the block in the configured default element, unless the annotation chose one (or a `summary`,
+    /// which needs `<details>`, or a `caption`, which needs `<figure>`).
+    fn with_default_tag(mut self, default_tag: &Option<String>) -> Attributes {
+        if self.tag.is_none() && self.summary.is_none() && self.caption.is_none() {
+            self.tag = default_tag.clone();
+        }
+        self
+    }
+
+    /// Follow each alias class with the classes it stands for, so `{:.warn}` can mean `.warn .admonition .warning`.
+    fn expand_aliases(mut self, aliases: &HashMap<String, Vec<String>>) -> Attributes {
+        if aliases.is_empty() {
+            return self;
+        }
+        let classes = std::mem::take(&mut self.classes);
+        for class in classes {
+            let expansion = aliases.get(&class).into_iter().flatten();
+            self.add_class(&class);
+            for class in expansion {
+                self.add_class(class);
+            }
+        }
+        self
+    }
+
+    /// Give the block `class`, unless it already has it: classes are kept in the order first given.
+    fn add_class(&mut self, class: &str) {
+        if !self.classes.iter().any(|c| c == class) {
+            self.classes.push(class.to_string());
+        }
+    }
+
+    fn set_id(&mut self, id: &str) -> Result<(), String> {
+        if self.id.is_some() {
+            return Err("sets more than one id".to_string());
+        }
+        if id.is_empty() {
+            return Err("has an empty id".to_string());
+        }
+        self.id = Some(id.to_string());
+        Ok(())
+    }
+
+    fn set_style(&mut self, style: &str) -> Result<(), String> {
+        if self.style().is_some() {
+            return Err("sets more than one style".to_string());
+        }
+        // Escaping would keep these from breaking out of the attribute, but in css they can only be a mistake.
+        if style.contains(&['"', '<', '>'][..]) {
+            return Err(format!("has `\"`, `<` or `>` in its style `{}`", style));
+        }
+        self.others.push(("style".to_string(), style.to_string()));
+        Ok(())
+    }
+
+    fn style(&self) -> Option<&str> {
+        self.others
+            .iter()
+            .find(|(key, _)| key == "style")
+            .map(|(_, style)| style.as_str())
+    }
+
+    fn set_tag(&mut self, tag: &str) -> Result<(), String> {
+        if self.tag.is_some() {
+            return Err("sets more than one tag".to_string());
+        }
+        if !is_tag_name(tag) {
+            return Err(format!(
+                "has the tag `{}`, which isn't one of {}",
+                tag,
+                BLOCK_TAGS.join(", ")
+            ));
+        }
+        self.tag = Some(tag.to_string());
+        Ok(())
+    }
+
+    /// Label the block for screen readers, keeping any `role` or `aria-label` the author set by hand.
+    fn add_aria(&mut self, role: &str, label: &str) {
+        for (key, value) in &[("role", role), ("aria-label", label)] {
+            if !self.others.iter().any(|(k, _)| k == key) {
+                self.others.push((key.to_string(), value.to_string()));
+            }
+        }
+    }
+
+    /// Fold in the attributes of the block right after this one, for the two to share a wrapper.  Returns
+    /// false, changing nothing, if they can't: they want different elements or both an id, set the same
+    /// attribute, or one has a title, summary or caption, which only fits at the start or end of a block.
+    fn merge(&mut self, next: Attributes) -> bool {
+        let fits = self.tag() == next.tag()
+            && !(self.id.is_some() && next.id.is_some())
+            && !next
+                .others
+                .iter()
+                .any(|(key, _)| self.others.iter().any(|(k, _)| k == key))
+            && [
+                &self.title,
+                &self.summary,
+                &self.caption,
+                &next.title,
+                &next.summary,
+                &next.caption,
+            ]
+            .iter()
+            .all(|caption| caption.is_none());
+        if !fits {
+            return false;
+        }
+        self.id = self.id.take().or(next.id);
+        for class in &next.classes {
+            self.add_class(class);
+        }
+        self.others.extend(next.others);
+        true
+    }
+
+    /// The name of the wrapping element, `div` unless the annotation asked for another.
+    fn tag(&self) -> &str {
+        match (&self.tag, &self.summary, &self.caption) {
+            (Some(tag), _, _) => tag,
+            (None, Some(_), _) => "details",
+            (None, None, Some(_)) => "figure",
+            (None, None, None) => "div",
+        }
+    }
+
+    /// The `<summary>` a `<details>` block has to start with.
+    fn summary_element(&self) -> Option<String> {
+        if self.tag() != "details" {
+            return None;
+        }
+        let summary = self.summary.as_deref().unwrap_or(DEFAULT_SUMMARY);
+        Some(format!("<summary>{}</summary>", escape_html(summary)))
+    }
+
+    /// Render the opening tag carrying these attributes.
+    /// Values are html-escaped, so an annotation like `{:.foo"><script>}` can't break out of the tag.
+    fn start_tag(&self) -> String {
+        let mut start = format!("<{}", self.tag());
+        if let Some(id) = &self.id {
+            start.push_str(&format!(" id=\"{}\"", escape_html(id)));
+        }
+        if !self.classes.is_empty() {
+            start.push_str(&format!(
+                " class=\"{}\"",
+                escape_html(&self.classes.join(" "))
+            ));
+        }
+        for (key, value) in &self.others {
+            start.push_str(&format!(" {}=\"{}\"", key, escape_html(value)));
+        }
+        start.push('>');
+        start
+    }
+
+    /// Render the matching closing tag, after the caption, if there is one.
+    fn end_tag(&self) -> String {
+        match &self.caption {
+            Some(caption) => format!(
+                "<figcaption>{}</figcaption></{}>",
+                escape_html(caption),
+                self.tag()
+            ),
+            None => format!("</{}>", self.tag()),
+        }
+    }
+
+    /// What the annotation asked for, like `note small #intro`, to name the block in a debug comment.
+    fn describe(&self) -> String {
+        let mut parts = self.classes.clone();
+        parts.extend(self.id.iter().map(|id| format!("#{}", id)));
+        if parts.is_empty() {
+            parts.push(self.tag().to_string());
+        }
+        parts.join(" ")
+    }
+}
+
+/// The elements a block may be wrapped in: ones whose content is rendered like any other.  The likes of
+/// `script`, `style` or `textarea` would hide the block's markdown or run it.
+const BLOCK_TAGS: &[&str] = &[
+    "div", "section", "aside", "article", "figure", "details", "nav", "header", "footer", "main",
+];
+
+/// Whether `tag` will do as the name of the wrapping element.
+fn is_tag_name(tag: &str) -> bool {
+    BLOCK_TAGS.contains(&tag)
+}
+
+/// Replace the characters that are special inside a quoted html attribute value.
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Describe a problem with an annotation, given as written, like `{:#a #b}`.
+fn annotation_error(annotation: &str, problem: &str) -> Error {
+    Error::msg(format!("class annotation `{}` {}", annotation, problem))
+}
+
+/// Split an annotation on whitespace, keeping quoted attribute values like `title="two words"` whole.
+fn split_tokens(annotation: &str) -> Result<Vec<&str>, String> {
+    let mut tokens = vec![];
+    let mut quote = None;
+    let mut start = None;
+    for (i, c) in annotation.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c.is_whitespace() => {
+                if let Some(s) = start.take() {
+                    tokens.push(&annotation[s..i]);
+                }
+                continue;
+            }
+            None => {}
+        }
+        start.get_or_insert(i);
+    }
+    if quote.is_some() {
+        return Err("has an unterminated quote".to_string());
+    }
+    if let Some(s) = start {
+        tokens.push(&annotation[s..]);
+    }
+    Ok(tokens)
+}
+
+/// Check a `key=value` pair, removing the quotes around the value if it has them.
+fn parse_pair<'a>(key: &'a str, value: &'a str) -> Result<(&'a str, &'a str), String> {
+    let key_is_valid = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == ':');
+    if !key_is_valid {
+        return Err(format!("has an invalid attribute name `{}`", key));
+    }
+    let unquoted = match value.chars().next() {
+        Some(q) if q == '"' || q == '\'' => {
+            if value.len() < 2 || !value.ends_with(q) || value[1..value.len() - 1].contains(q) {
+                None
+            } else {
+                Some(&value[1..value.len() - 1])
+            }
+        }
+        _ if value.contains('"') || value.contains('\'') => None,
+        _ => Some(value),
+    };
+    match unquoted {
+        Some(value) => Ok((key, value)),
+        None => Err(format!("has a badly quoted value for `{}`", key)),
+    }
+}
+
+/// Recognize a class annotation like `{:.class-name}` or `{:#id}` and return what's between its opening and
+/// closing tokens (`{:` and `}` unless configured otherwise).
+/// Whitespace just inside the tokens is ignored, so `{: .class-name }` works too.
+fn annotation_body<'t>(text: &'t str, options: &Options) -> Option<&'t str> {
+    // Trailing whitespace (a stray space, or the `\r` of a CRLF line end) isn't part of the annotation.
+    let body = text
+        .trim_end()
+        .strip_prefix(options.open_token())?
+        .strip_suffix(options.close_token())?
+        .trim();
+    if body.starts_with('.') || body.starts_with('#') {
+        Some(body)
+    } else {
+        None
+    }
+}
+
+/// What follows the colons of a fence line, if `line` is one: three or more colons, then the classes or
+/// annotation of the fenced div it opens, or nothing for the line that closes one.
+fn fence_body(line: &str) -> Option<&str> {
+    let line = line.trim();
+    let body = line.trim_start_matches(':');
+    if line.len() - body.len() < 3 {
+        return None;
+    }
+    Some(body.trim())
+}
+
+/// Apply the class annotations in a chapter's markdown and return the rewritten markdown.
+/// This is the whole preprocessor minus the mdbook plumbing, for tools that want to embed it.
+/// Fails, listing every malformed annotation one per line with its line number, if any annotation can't be
+/// parsed; and fails too if the annotated chapter can't be written back as markdown, or annotates more
+/// blocks than `max-blocks` allows.  Text that is nearly an annotation, like `{.note}`, is only warned about.
+pub fn annotate(content: &str) -> Result<String, Error> {
+    annotate_with(content, &Config::default())
+}
+
+/// Like `annotate`, with the settings of a `[preprocessor.classy]` table.
+pub fn annotate_with(content: &str, config: &Config) -> Result<String, Error> {
+    let options = Options::new(config.clone())?;
+    let transformed = transform(content, &options);
+    match transformed.failure {
+        Some(Failure::TooManyBlocks) => {
+            return Err(Error::msg(format!(
+                "the chapter annotates more than {} blocks, the most `max-blocks` allows",
+                options.max_blocks()
+            )))
+        }
+        Some(Failure::Unrendered) => {
+            return Err(Error::msg(
+                "couldn't write the annotated chapter back as markdown",
+            ))
+        }
+        None => {}
+    }
+    for near_miss in &transformed.near_misses {
+        warn!("line {}: {}", near_miss.line, near_miss.error);
+    }
+    if !transformed.problems.is_empty() {
+        let report: Vec<String> = transformed
+            .problems
+            .iter()
+            .map(|problem| format!("line {}: {}", problem.line, problem.error))
+            .collect();
+        return Err(Error::msg(report.join("\n")));
+    }
+    Ok(transformed.content)
+}
+
+/// The book.toml `config` with an empty `[preprocessor.<name>]` table added, or `None` if it already has one.
+/// The table is appended rather than the file re-serialized, so the user's formatting and comments survive.
+/// Fails if `config` isn't valid toml.
+pub fn install_table(config: &str, name: &str) -> Result<Option<String>, Error> {
+    let parsed: toml::Value = toml::from_str(config)?;
+    let installed = parsed
+        .get("preprocessor")
+        .and_then(|preprocessors| preprocessors.get(name))
+        .is_some();
+    if installed {
+        return Ok(None);
+    }
+    let mut config = config.to_string();
+    if !config.is_empty() && !config.ends_with('\n') {
+        config.push('\n');
+    }
+    config.push_str(&format!("\n[preprocessor.{}]\n", name));
+    Ok(Some(config))
+}
+
+/// Mutation: the payload here is that it edits a chapter's content.
+/// Returns what was done to the chapter, along with its malformed annotations, which are left in place and
+/// reported one each, prefixed with the chapter's `location` and the line.  Near misses are warned about
+/// here, the same way.  A chapter with
+/// more blocks than `max-blocks` allows is left as it was and fails with a `TooManyBlocks`; one the serializer
+/// can't write back is left as it was too, with a warning, and the rest of the book goes on.
+fn classy(
+    content: &mut String,
+    location: &str,
+    level: usize,
+    options: &Options,
+) -> Result<AnnotatedChapter, Error> {
+    let transformed = transform_chapter(content, Some(level), options);
+    match transformed.failure {
+        Some(Failure::TooManyBlocks) => {
+            return Err(Error::new(TooManyBlocks {
+                location: location.to_string(),
+                max_blocks: options.max_blocks(),
+            }))
+        }
+        Some(Failure::Unrendered) => {
+            warn!(
+                "{}: couldn't write the annotated chapter back as markdown, so it was left as it was",
+                location
+            );
+            return Ok((Counts::default(), vec![]));
+        }
+        None => {}
+    }
+    *content = transformed.content;
+    let counts = transformed.counts;
+    debug!(
+        "{}: {} block(s) annotated, nested {} deep",
+        location, counts.blocks, counts.depth
+    );
+    for near_miss in &transformed.near_misses {
+        warn!("{}:{}: {}", location, near_miss.line, near_miss.error);
+    }
+    let problems = transformed
+        .problems
+        .iter()
+        .map(|problem| format!("{}:{}: {}", location, problem.line, problem.error))
+        .collect();
+    Ok((counts, problems))
+}
+
+/// One line on how much of the book was annotated, given each chapter's counts.
+fn stats_summary(counts: &[(String, Counts)]) -> String {
+    let blocks: usize = counts.iter().map(|(_, counts)| counts.blocks).sum();
+    let chapters = counts
+        .iter()
+        .filter(|(_, counts)| counts.blocks > 0)
+        .count();
+    let depth = counts
+        .iter()
+        .map(|(_, counts)| counts.depth)
+        .max()
+        .unwrap_or(0);
+    let mut summary = format!(
+        "classy: {} block(s) annotated in {} of {} chapter(s), nested at most {} deep",
+        blocks,
+        chapters,
+        counts.len(),
+        depth
+    );
+    // The first of the busiest chapters, in book order.
+    let busiest = counts
+        .iter()
+        .rev()
+        .max_by_key(|(_, counts)| counts.blocks)
+        .filter(|(_, counts)| counts.blocks > 0);
+    if let Some((location, busiest)) = busiest {
+        summary.push_str(&format!("; most in {} ({})", location, busiest.blocks));
+    }
+    summary
+}
+
+/// What annotating one chapter came to.
+struct Transformed {
+    content: String,
+    /// Annotations that couldn't be parsed, left in the content as plain text.
+    problems: Vec<Problem>,
+    /// Text that is nearly an annotation, to be warned about.
+    near_misses: Vec<Problem>,
+    counts: Counts,
+    /// Why the chapter was left as it was, if something went wrong with it as a whole.
+    failure: Option<Failure>,
+}
+
+/// What can keep a chapter from being annotated at all.
+enum Failure {
+    /// It annotates more blocks than `max-blocks` allows.
+    TooManyBlocks,
+    /// The serializer couldn't write the annotated events back as markdown.
+    Unrendered,
+}
+
+/// A chapter annotating more blocks than `max-blocks` allows.  Unlike a problem with an annotation, this
+/// fails the build in any mode: such a chapter was generated by something gone wrong.
+#[derive(Debug)]
+struct TooManyBlocks {
+    location: String,
+    max_blocks: usize,
+}
+
+impl fmt::Display for TooManyBlocks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: annotates more than {} blocks, the most `max-blocks` allows",
+            self.location, self.max_blocks
+        )
+    }
+}
+
+impl std::error::Error for TooManyBlocks {}
+
+/// An annotation that couldn't be parsed.
+struct Problem {
+    /// Where the annotation is in the chapter, counting from 1.
+    line: usize,
+    error: Error,
+}
+
+/// How much annotating a chapter did.
+#[derive(Clone, Debug, Default)]
+struct Counts {
+    /// How many blocks were wrapped.
+    blocks: usize,
+    /// The most wrappers open at once: more than one when an annotated list item or alert holds annotated
+    /// blocks.
+    depth: usize,
+    /// How many blocks were given each class, as it was written out.
+    classes: HashMap<String, usize>,
+}
+
+/// The problems found annotating a book, and the counts of each of its chapters.
+type Annotated = (Vec<String>, Vec<(String, Counts)>);
+
+/// What was done to a chapter, and the problems found in it.
+type AnnotatedChapter = (Counts, Vec<String>);
+
+/// Annotate a chapter's blocks, then wrap the whole chapter in `chapter-class`, if there is one.
+fn transform(content: &str, options: &Options) -> Transformed {
+    transform_chapter(content, None, options)
+}
+
+/// Like `transform`, for a chapter `level` deep in the book's table of contents if that's known:
+/// `chapter-level-class` adds a class for it to the chapter's wrapper.
+fn transform_chapter(content: &str, level: Option<usize>, options: &Options) -> Transformed {
+    let mut transformed = annotate_blocks(content, options);
+    let mut classes: Vec<String> = options
+        .chapter_class
+        .iter()
+        .flat_map(|classes| classes.split_whitespace())
+        .map(|class| options.prefixed(class))
+        .collect();
+    if let (true, Some(level)) = (options.chapter_level_class, level) {
+        classes.push(options.prefixed(&format!("chapter-level-{}", level)));
+    }
+    if !classes.is_empty() {
+        transformed.content = wrap_chapter(&transformed.content, &classes);
+    }
+    transformed
+}
+
+/// Wrap everything after the frontmatter in a div of `classes`, standing apart from the markdown.
+fn wrap_chapter(content: &str, classes: &[String]) -> String {
+    let (frontmatter, body) = split_frontmatter(content);
+    format!(
+        "{}<div class=\"{}\">\n\n{}\n\n</div>\n",
+        frontmatter,
+        escape_html(&classes.join(" ")),
+        body.trim_matches(&['\r', '\n'][..])
+    )
+}
+
+/// This is where the markdown transformation actually happens.
+/// Take paragraphs beginning with `{:.class-name}` (or `{:#id.class-name}`) and give them special rendering.
+/// Returns the new markdown along with any annotations that couldn't be parsed.
+fn annotate_blocks(content: &str, options: &Options) -> Transformed {
+    annotate_blocks_into(content, options, String::with_capacity(content.len() + 128))
+}
+
+/// Like `annotate_blocks`, writing the new markdown into `buf`.
+fn annotate_blocks_into<W: fmt::Write + Into<String>>(
+    content: &str,
+    options: &Options,
+    mut buf: W,
+) -> Transformed {
+    let (frontmatter, body) = split_frontmatter(content);
+    let problems_in = |problems: Vec<(usize, Error)>| -> Vec<Problem> {
+        problems
+            .into_iter()
+            .map(|(offset, error)| Problem {
+                line: content[..frontmatter.len() + offset].matches('\n').count() + 1,
+                error,
+            })
+            .collect()
+    };
+    let untouched = |problems, near_misses| Transformed {
+        content: content.to_string(),
+        problems,
+        near_misses,
+        counts: Counts::default(),
+        failure: None,
+    };
+
+    // 0. Round-tripping through the serializer reflows the markdown, so leave chapters alone that can't
+    //    contain anything to transform.
+    if !has_markers(body, options) {
+        return untouched(vec![], vec![]);
+    }
+
+    // 1. Stream the parsed markdown through the annotator, which wraps annotated paragraphs as they go by.
+    //    Frontmatter isn't markdown, so it stays out of the parser (which would take it for a rule and a
+    //    heading) and is put back as it was.
+    //    The markdown is read with the extensions mdbook renders it with (tables, footnotes, strikethrough
+    //    and task lists): what the parser doesn't know as markup, the serializer escapes into plain text.
+    let parser = markdown_parser(body);
+    let mut annotator = ClassAnnotator::new(parser.into_offset_iter(), body, options);
+
+    // 2. Generate markdown from the transformed events.  Should the serializer fail, the chapter is left as it
+    //    was rather than taking the book down with it.
+    // The serializer would drop the blank lines between the frontmatter and the markdown.
+    let blank_lines = &body[..body.len() - body.trim_start_matches(&['\r', '\n'][..]).len()];
+    let rendered = buf
+        .write_str(frontmatter)
+        .and_then(|_| buf.write_str(blank_lines))
+        .and_then(|_| render(&mut annotator, &mut buf));
+    let failure = if rendered.is_err() {
+        Some(Failure::Unrendered)
+    } else if annotator.too_many_blocks {
+        Some(Failure::TooManyBlocks)
+    } else {
+        None
+    };
+    if failure.is_some() {
+        return Transformed {
+            failure,
+            ..untouched(vec![], vec![])
+        };
+    }
+
+    // 3. Markers that turned out to be something else (in code, mid-paragraph, malformed) changed nothing,
+    //    so don't let the round trip reflow the chapter for them.
+    if annotator.counts.blocks == 0 {
+        return untouched(
+            problems_in(annotator.problems),
+            problems_in(annotator.near_misses),
+        );
+    }
+    Transformed {
+        content: buf.into(),
+        problems: problems_in(annotator.problems),
+        near_misses: problems_in(annotator.near_misses),
+        counts: annotator.counts,
+        failure: None,
+    }
+}
+
+/// The id mdbook gives a heading with the html `content`, before telling apart headings with the same one:
+/// the text without tags or entities, lowercased, with spaces as `-` and other punctuation dropped.
+fn heading_id(content: &str) -> String {
+    let mut text = String::with_capacity(content.len());
+    let mut in_tag = false;
+    for c in content.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    for entity in &["&lt;", "&gt;", "&amp;", "&#39;", "&quot;"] {
+        text = text.replace(entity, "");
+    }
+    text.trim()
+        .trim_start_matches('#')
+        .trim()
+        .chars()
+        .filter_map(|c| {
+            if c.is_alphanumeric() || c == '_' || c == '-' {
+                Some(c.to_ascii_lowercase())
+            } else if c.is_whitespace() {
+                Some('-')
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// `event` with copies of the text it borrows, for events parsed from text that doesn't outlive them.
+fn owned_event<'a>(event: Event) -> Event<'a> {
+    let owned = |text: CowStr| CowStr::from(text.into_string());
+    match event {
+        Event::Start(tag) => Event::Start(owned_tag(tag)),
+        Event::End(tag) => Event::End(owned_tag(tag)),
+        Event::Text(text) => Event::Text(owned(text)),
+        Event::Code(code) => Event::Code(owned(code)),
+        Event::Html(html) => Event::Html(owned(html)),
+        Event::FootnoteReference(label) => Event::FootnoteReference(owned(label)),
+        Event::SoftBreak => Event::SoftBreak,
+        Event::HardBreak => Event::HardBreak,
+        Event::Rule => Event::Rule,
+        Event::TaskListMarker(checked) => Event::TaskListMarker(checked),
+    }
+}
+
+/// `tag` with copies of the text it borrows, like `owned_event`.
+fn owned_tag<'a>(tag: Tag) -> Tag<'a> {
+    let owned = |text: CowStr| CowStr::from(text.into_string());
+    match tag {
+        Tag::Paragraph => Tag::Paragraph,
+        Tag::Heading(level) => Tag::Heading(level),
+        Tag::BlockQuote => Tag::BlockQuote,
+        Tag::CodeBlock(CodeBlockKind::Indented) => Tag::CodeBlock(CodeBlockKind::Indented),
+        Tag::CodeBlock(CodeBlockKind::Fenced(info)) => {
+            Tag::CodeBlock(CodeBlockKind::Fenced(owned(info)))
+        }
+        Tag::List(start) => Tag::List(start),
+        Tag::Item => Tag::Item,
+        Tag::FootnoteDefinition(label) => Tag::FootnoteDefinition(owned(label)),
+        Tag::Table(alignments) => Tag::Table(alignments),
+        Tag::TableHead => Tag::TableHead,
+        Tag::TableRow => Tag::TableRow,
+        Tag::TableCell => Tag::TableCell,
+        Tag::Emphasis => Tag::Emphasis,
+        Tag::Strong => Tag::Strong,
+        Tag::Strikethrough => Tag::Strikethrough,
+        Tag::Link(kind, url, title) => Tag::Link(kind, owned(url), owned(title)),
+        Tag::Image(kind, url, title) => Tag::Image(kind, owned(url), owned(title)),
+    }
+}
+
+/// The ids mdbook gives the headings of `source` that aren't annotated, which it numbers among themselves:
+/// `intro`, then `intro-1` for the next with the same text, and so on.  Annotated headings are written as
+/// html with their own ids, which mdbook leaves alone, so theirs have to keep clear of these.
+fn plain_heading_ids(source: &str, options: &Options) -> HashSet<String> {
+    let mut ids = HashSet::new();
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    let mut heading: Option<Vec<Event>> = None;
+    for event in markdown_parser(source) {
+        match event {
+            Event::Start(Tag::Heading(_)) => heading = Some(vec![]),
+            Event::End(Tag::Heading(_)) => {
+                let inline = heading.take().unwrap_or_default();
+                let text: String = inline
+                    .iter()
+                    .filter_map(|event| match event {
+                        Event::Text(text) => Some(text.as_ref()),
+                        _ => None,
+                    })
+                    .collect();
+                let trimmed = text.trim_end();
+                let annotated = trimmed.rfind(options.open_token()).is_some_and(|start| {
+                    annotation_body(&trimmed[start..], options)
+                        .is_some_and(|body| Attributes::parse(body).is_ok())
+                });
+                if annotated {
+                    continue;
+                }
+                let mut content = String::new();
+                pulldown_cmark::html::push_html(&mut content, inline.into_iter());
+                let id = heading_id(&content);
+                let count = counts.entry(id.clone()).or_insert(0);
+                ids.insert(match *count {
+                    0 => id,
+                    n => format!("{}-{}", id, n),
+                });
+                *count += 1;
+            }
+            event => {
+                if let Some(inline) = &mut heading {
+                    inline.push(event);
+                }
+            }
+        }
+    }
+    ids
+}
+
+/// A parser for `text` with the extensions mdbook renders markdown with: tables, footnotes, strikethrough and
+/// task lists.  Built here rather than taken from mdbook, whose later 0.4 releases parse with a newer
+/// pulldown-cmark than the serializer speaks.
+fn markdown_parser(text: &str) -> Parser<'_> {
+    let mut extensions = pulldown_cmark::Options::empty();
+    extensions.insert(pulldown_cmark::Options::ENABLE_TABLES);
+    extensions.insert(pulldown_cmark::Options::ENABLE_FOOTNOTES);
+    extensions.insert(pulldown_cmark::Options::ENABLE_STRIKETHROUGH);
+    extensions.insert(pulldown_cmark::Options::ENABLE_TASKLISTS);
+    Parser::new_ext(text, extensions)
+}
+
+/// Split off the YAML frontmatter a chapter starts with, if it has any: a `---` first line, followed directly
+/// (not by a blank line, as a thematic break would likely be) by the yaml and a closing `---` or `...` line.
+/// Returns the frontmatter, including its closing line, and the rest of the chapter.
+fn split_frontmatter(content: &str) -> (&str, &str) {
+    let not_frontmatter = ("", content);
+    let yaml = match content
+        .strip_prefix("---\n")
+        .or_else(|| content.strip_prefix("---\r\n"))
+    {
+        Some(yaml) => yaml,
+        None => return not_frontmatter,
+    };
+    match yaml.lines().next() {
+        Some(line) if !line.trim().is_empty() => {}
+        _ => return not_frontmatter,
+    }
+    let mut end = content.len() - yaml.len();
+    for line in yaml.split_inclusive('\n') {
+        end += line.len();
+        if matches!(line.trim_end(), "---" | "...") {
+            return content.split_at(end);
+        }
+    }
+    not_frontmatter
+}
+
+/// A cheap check for whether `content` could hold an annotation (or, when enabled, a GitHub alert marker or
+/// a fenced div), or something nearly one to report.  False positives only cost a round trip through the
+/// parser.
+fn has_markers(content: &str, options: &Options) -> bool {
+    content.contains(options.open_token())
+        || (options.github_alerts && content.contains("[!"))
+        || (options.fenced_divs && content.contains(":::"))
+        || (!options.ignore_near_misses && content.contains("{."))
+}
+
+/// What's wrong with `line`, if it looks like an annotation with a typo that keeps it from being one:
+/// `{.note}`, missing the colon, or `{:note}`, missing the dot.
+fn near_miss(line: &str, options: &Options) -> Option<String> {
+    let line = line.trim_end();
+    let looks_like_classes = |body: &str| {
+        !body.is_empty()
+            && body
+                .chars()
+                .all(|c| c.is_alphanumeric() || " .#-_".contains(c))
+    };
+    if options.open_token() == "{:" {
+        if let Some(body) = line
+            .strip_prefix('{')
+            .and_then(|rest| rest.strip_suffix('}'))
+        {
+            if (body.starts_with('.') || body.starts_with('#')) && looks_like_classes(body) {
+                return Some(format!("is missing its `:`; write `{{:{}}}`", body));
+            }
+        }
+    }
+    let body = line
+        .strip_prefix(options.open_token())?
+        .strip_suffix(options.close_token())?
+        .trim();
+    if looks_like_classes(body) && body.starts_with(char::is_alphanumeric) {
+        return Some(format!(
+            "is missing the `.` before its class; write `{}.{}{}`",
+            options.open_token(),
+            body,
+            options.close_token()
+        ));
+    }
+    None
+}
+
+/// Serialize events back to markdown.
+/// After html, pulldown-cmark-to-cmark starts the next block with a bare newline, which ends an enclosing
+/// blockquote or list item early, and isn't enough to end the html block itself, so the annotated markdown
+/// would no longer parse the way it was written.  Feeding the serializer one event at a time lets us write
+/// that newline with the container's padding, and follow it with a blank line where a block starts.
+/// Html inside a paragraph, like a `<br>` or a `<span>`, is part of the text, so nothing is added after it:
+/// the serializer's newline would break the paragraph in two.
+fn render<'e>(events: impl Iterator<Item = Event<'e>>, buf: &mut impl fmt::Write) -> fmt::Result {
+    let mut state = State::default();
+    // Whether we are in inline content, where html is inline html.
+    let mut inline = false;
+    for event in events {
+        if state.last_was_html && !matches!(event, Event::Html(_) | Event::Text(_)) {
+            state.last_was_html = false;
+            if !inline {
+                buf.write_char('\n')?;
+                for padding in &state.padding {
+                    buf.write_str(padding)?;
+                }
+                if ends_inline_content(&event) {
+                    // An html block only ends at a blank line, so markdown blocks need one after it.
+                    state.newlines_before_start = state.newlines_before_start.max(1);
+                }
+            }
+        }
+        inline = match &event {
+            Event::Start(Tag::Paragraph)
+            | Event::Start(Tag::Heading(_))
+            | Event::Start(Tag::TableCell) => true,
+            Event::Html(_) => inline,
+            event => !ends_inline_content(event),
+        };
+        state = pulldown_cmark_to_cmark::cmark(std::iter::once(event), &mut *buf, Some(state))?;
+    }
+    Ok(())
+}
+
+/// An event iterator adapter that replaces the `{:.class-name}` annotation at the start of a paragraph with
+/// `<div class="class-name">` before the paragraph and `</div>` after it.
+/// It only looks ahead a few events, or to the end of a heading, or of a paragraph for `trailing-markers` and
+/// `merge-adjacent`, so the chapter's events are never collected into a vector.
+struct ClassAnnotator<'a, 'o, I: Iterator<Item = (Event<'a>, Range<usize>)>> {
+    /// The parser's events, each with where in the chapter it came from.
+    events: I,
+    /// The markdown the events were parsed from.
+    source: &'a str,
+    options: &'o Options,
+    /// Events read ahead from the parser but not processed yet.
+    lookahead: VecDeque<(Event<'a>, Range<usize>)>,
+    /// Events that have been produced but not yet handed out.
+    queued: VecDeque<Event<'a>>,
+    /// The closing tag owed at the end of the current paragraph, if it was annotated.
+    closing: Option<String>,
+    /// For each list item we are inside, the closing tag owed at its end if it was annotated.
+    items: Vec<Option<String>>,
+    /// Whether we are in the paragraph given to an annotated tight list item's text, which ends with the
+    /// item's inline content.
+    item_text: bool,
+    /// A `next` annotation that was a paragraph of its own, waiting for the block it applies to, and where
+    /// it was.
+    pending: Option<(Attributes, usize)>,
+    /// How many tags are open at the moment.
+    open_tags: usize,
+    /// The closing tags owed to blocks opened for `next` annotations, each with the `open_tags` count that
+    /// marks the block's end.
+    wrapped: Vec<(usize, String)>,
+    /// For each blockquote we are inside, whether it was turned into an alert block.
+    quotes: Vec<bool>,
+    /// For each annotated list item or `next` block we are inside, the class its children are scoped by
+    /// with `inherit-parent-class`, if it has one.
+    scopes: Vec<Option<String>>,
+    /// The ids the chapter's headings have: the plain ones' as mdbook will number them, read when the first
+    /// annotated heading needs them, and those given to annotated headings so far.
+    heading_ids: Option<HashSet<String>>,
+    /// Whether the end of the heading just turned into html is yet to be dropped.
+    heading_end: bool,
+    /// The fenced divs open at the moment, innermost last: each with the `open_tags` count around it, its
+    /// closing tag, and where its opening fence is.
+    fences: Vec<(usize, String, usize)>,
+    /// How many of the annotated paragraphs coming up share the current one's wrapper, with `merge-adjacent`.
+    merges: usize,
+    /// Whether the next paragraph is one of those, whose annotation is to be dropped.
+    merging: bool,
+    /// The annotated blocks and alerts turned into html so far.
+    counts: Counts,
+    /// Whether more blocks were opened than `max-blocks` allows, which ends the events there.
+    too_many_blocks: bool,
+    /// Annotations that couldn't be parsed, left in the output as plain text, by where they start.
+    problems: Vec<(usize, Error)>,
+    /// Text that is nearly an annotation, like `{.note}`, by where it starts: worth a warning, not a problem.
+    near_misses: Vec<(usize, Error)>,
+}
+
+impl<'a, 'o, I: Iterator<Item = (Event<'a>, Range<usize>)>> ClassAnnotator<'a, 'o, I> {
+    fn new(events: I, source: &'a str, options: &'o Options) -> Self {
+        ClassAnnotator {
+            events,
+            source,
+            options,
+            lookahead: VecDeque::new(),
+            queued: VecDeque::new(),
+            closing: None,
+            items: vec![],
+            item_text: false,
+            pending: None,
+            open_tags: 0,
+            wrapped: vec![],
+            quotes: vec![],
+            scopes: vec![],
+            heading_ids: None,
+            heading_end: false,
+            fences: vec![],
+            merges: 0,
+            merging: false,
+            counts: Counts::default(),
+            too_many_blocks: false,
+            problems: vec![],
+            near_misses: vec![],
+        }
+    }
+
+    /// Having just seen a blockquote start, consume a GitHub alert marker like `[!NOTE]` heading its first
+    /// paragraph and return the alert's kind.
+    fn take_alert(&mut self) -> Option<&'static str> {
+        if !self.options.github_alerts {
+            return None;
+        }
+        if !matches!(self.peek(0), Some(Event::Start(Tag::Paragraph))) {
+            return None;
+        }
+        // The parser splits `[!NOTE]` into several text events, so look at the whole run of text.
+        let mut marker = String::new();
+        let mut texts = 0;
+        while let Some(Event::Text(text)) = self.peek(texts + 1) {
+            marker.push_str(text);
+            texts += 1;
+        }
+        let kind = marker
+            .strip_prefix("[!")
+            .and_then(|rest| rest.strip_suffix(']'))
+            .and_then(|kind| {
+                GITHUB_ALERTS
+                    .iter()
+                    .find(|alert| alert.eq_ignore_ascii_case(kind))
+            })?;
+        self.lookahead.drain(1..texts + 1);
+        match self.peek(1) {
+            Some(Event::SoftBreak) => {
+                self.lookahead.remove(1);
+            }
+            Some(Event::End(Tag::Paragraph)) => {
+                // The marker was the whole paragraph, so drop the paragraph too.
+                self.lookahead.drain(0..2);
+            }
+            _ => {}
+        }
+        Some(kind)
+    }
+
+    /// The first admonition preset among the annotation's classes, if admonitions are turned on.
+    fn admonition_for(&self, attributes: &Attributes) -> Option<&'o Admonition> {
+        let presets = self.options.admonitions.as_ref()?;
+        attributes
+            .classes
+            .iter()
+            .find_map(|class| presets.get(class))
+    }
+
+    /// The next unprocessed event.
+    fn pull(&mut self) -> Option<(Event<'a>, Range<usize>)> {
+        self.lookahead.pop_front().or_else(|| self.events.next())
+    }
+
+    /// Report a waiting `next` annotation that didn't get a block to wrap: the chapter or its container ended,
+    /// or what followed was a rule or raw html.
+    fn abandon_pending(&mut self) {
+        if let Some((_, offset)) = self.pending.take() {
+            let error =
+                Error::msg("class annotation with `next` isn't followed by a block it can wrap");
+            self.problems.push((offset, error));
+        }
+    }
+
+    /// Where in the chapter the unprocessed event `n` places ahead starts.
+    fn offset(&mut self, n: usize) -> Option<usize> {
+        self.peek(n)?;
+        self.lookahead.get(n).map(|(_, range)| range.start)
+    }
+
+    /// Look at the unprocessed event `n` places ahead without consuming anything.
+    fn peek(&mut self, n: usize) -> Option<&Event<'a>> {
+        while self.lookahead.len() <= n {
+            let event = self.events.next()?;
+            self.lookahead.push_back(event);
+        }
+        self.lookahead.get(n).map(|(event, _)| event)
+    }
+
+    /// Consume the annotation `at` places ahead, if there is a well-formed one there, along with any stacked
+    /// on the lines below it, which count as written into the first.
+    /// A malformed one is left in place and returned as an error, along with where it starts, and so is one
+    /// with `next` that shares its paragraph with anything else.
+    fn annotation_at(&mut self, at: usize) -> Option<Result<Attributes, (usize, Error)>> {
+        let (first_line, pieces) = self.marker_at(at)?;
+        let mut body = annotation_body(&first_line, self.options)?.to_string();
+        let start = self.lookahead[at].1.start;
+        let mut attributes = match self.parse_body(&body, first_line.trim_end()) {
+            Ok(attributes) => attributes,
+            Err(e) => return Some(Err((start, e))),
+        };
+        let mut taken = self.take_marker_line(at, pieces);
+        while let Some((line, pieces)) = self.marker_at(at) {
+            let joined = match annotation_body(&line, self.options) {
+                Some(next) => format!("{} {}", body, next),
+                None => break,
+            };
+            match self.parse_body(&joined, line.trim_end()) {
+                Ok(stacked) => attributes = stacked,
+                Err(e) => {
+                    self.problems.push((self.lookahead[at].1.start, e));
+                    break;
+                }
+            }
+            body = joined;
+            taken.extend(self.take_marker_line(at, pieces));
+        }
+        if attributes.next && !matches!(self.peek(at), Some(Event::End(Tag::Paragraph))) {
+            // Nothing would be left to wrap the block after: put it back, as it was.
+            for (n, event) in taken.into_iter().enumerate() {
+                self.lookahead.insert(at + n, event);
+            }
+            let problem = "has `next`, which only works on an annotation of its own";
+            return Some(Err((
+                start,
+                annotation_error(first_line.trim_end(), problem),
+            )));
+        }
+        self.keep_escaped_marker(at);
+        Some(Ok(attributes))
+    }
+
+    /// Take out the `pieces` text events of an annotation `at` places ahead, and the line break after it.
+    fn take_marker_line(&mut self, at: usize, pieces: usize) -> Vec<(Event<'a>, Range<usize>)> {
+        let mut taken: Vec<_> = self.lookahead.drain(at..at + pieces).collect();
+        if let Some(Event::SoftBreak) = self.peek(at) {
+            taken.extend(self.lookahead.remove(at));
+        }
+        taken
+    }
+
+    /// The text `at` places ahead that could be an annotation, and how many text events it is made of.
+    fn marker_at(&mut self, at: usize) -> Option<(String, usize)> {
+        let options = self.options;
+        let opener = options.open_token().chars().next();
+        match self.peek(at) {
+            Some(Event::Text(text)) if text.chars().next() == opener => {}
+            _ => return None,
+        }
+        if self.escaped_at(at) {
+            return None;
+        }
+        // The parser splits text wherever markup could start, like at `[` or `&`, so unless the first piece is
+        // an annotation of its own, put the line back together and look at that.
+        let mut line = String::new();
+        let mut pieces = 0;
+        while let Some(Event::Text(text)) = self.peek(at + pieces) {
+            line.push_str(text);
+            pieces += 1;
+            if pieces == 1 && annotation_body(&line, options).is_some() {
+                break;
+            }
+        }
+        Some((line, pieces))
+    }
+
+    /// Whether the text `at` places ahead was escaped with a backslash in the chapter, as in `\{:.note}`,
+    /// to be shown as it is.
+    fn escaped_at(&mut self, at: usize) -> bool {
+        let start = match self.offset(at) {
+            Some(start) => start,
+            None => return false,
+        };
+        let backslashes = self.source[..start.min(self.source.len())]
+            .chars()
+            .rev()
+            .take_while(|&c| c == '\\')
+            .count();
+        // `\\` is an escaped backslash, not an escape.
+        backslashes % 2 == 1
+    }
+
+    /// Whether the text `at` places ahead is an annotation escaped with a backslash, as in `\{:.note}`, in
+    /// which case the backslash is put back in front of it.  The parser drops it, and without it the
+    /// annotation would be taken for a real one next time round.  It goes back as html, which is written as
+    /// it is: the serializer escapes a backslash starting text.
+    fn keep_escaped_marker(&mut self, at: usize) -> bool {
+        let open_token = self.options.open_token();
+        if !matches!(self.peek(at), Some(Event::Text(text)) if text.starts_with(open_token))
+            || !self.escaped_at(at)
+        {
+            return false;
+        }
+        let range = self.lookahead[at].1.clone();
+        self.lookahead
+            .insert(at, (Event::Html(CowStr::Borrowed("\\")), range));
+        true
+    }
+
+    /// With `merge-adjacent` on, having just taken the annotation of a paragraph, fold in those of the
+    /// annotated paragraphs directly after it that can share its wrapper, counting them in `merges`.
+    /// This reads ahead to the end of the last paragraph merged.
+    fn merge_following(&mut self, attributes: &mut Attributes) {
+        if !self.can_merge(attributes) {
+            return;
+        }
+        let mut end = 0;
+        loop {
+            // Paragraphs don't nest, so the first paragraph end is this one's.
+            while !matches!(self.peek(end), Some(Event::End(Tag::Paragraph)) | None) {
+                if self.fence_at(end).is_some() {
+                    // A fenced div opening or closing in between would be cut in half.
+                    return;
+                }
+                end += 1;
+            }
+            if !matches!(self.peek(end + 1), Some(Event::Start(Tag::Paragraph))) {
+                return;
+            }
+            let next = match self.marker_at(end + 2) {
+                Some((line, _)) => match self.parse_annotation(&line) {
+                    Some(Ok(next)) => next,
+                    _ => return,
+                },
+                None => return,
+            };
+            if !self.can_merge(&next) || !attributes.merge(next) {
+                return;
+            }
+            self.merges += 1;
+            end += 2;
+        }
+    }
+
+    /// Whether a block's html is just its tag, so that it can be merged with another's.
+    fn can_merge(&self, attributes: &Attributes) -> bool {
+        !attributes.next
+            && self.template_for(attributes).is_none()
+            && self.admonition_for(attributes).is_none()
+    }
+
+    /// Parse `marker`, if it is an annotation at all, into the attributes it asks for as the options have them.
+    fn parse_annotation(&self, marker: &str) -> Option<Result<Attributes, Error>> {
+        let body = annotation_body(marker, self.options)?;
+        Some(self.parse_body(body, marker.trim_end()))
+    }
+
+    /// Parse the inside of an annotation, naming it as `shown` in the error if it's malformed.
+    fn parse_body(&self, body: &str, shown: &str) -> Result<Attributes, Error> {
+        let options = self.options;
+        Attributes::parse(body)
+            .and_then(|attributes| attributes.validate(options))
+            .map(|attributes| attributes.expand_aliases(&options.aliases))
+            .map(|attributes| attributes.with_default_tag(&options.default_tag))
+            .map_err(|problem| annotation_error(shown, &problem))
+    }
+
+    /// The body of the fence line `at` places ahead, if fenced divs are on and the paragraph's line there is
+    /// one as a whole.
+    fn fence_at(&mut self, at: usize) -> Option<String> {
+        if !self.options.fenced_divs {
+            return None;
+        }
+        let body = match self.peek(at)? {
+            Event::Text(text) => fence_body(text)?.to_string(),
+            _ => return None,
+        };
+        match self.peek(at + 1)? {
+            Event::SoftBreak | Event::End(Tag::Paragraph) => Some(body),
+            _ => None,
+        }
+    }
+
+    /// Whether the innermost fenced div is open in the container of the paragraph we are in: the one a
+    /// closing fence here would close.
+    fn fence_closes_here(&self) -> bool {
+        self.fences_here() > 0
+    }
+
+    /// How many of the innermost fenced divs are open in the container of the paragraph we are in.
+    fn fences_here(&self) -> usize {
+        self.fences
+            .iter()
+            .rev()
+            .take_while(|(open_tags, ..)| *open_tags + 1 == self.open_tags)
+            .count()
+    }
+
+    /// Having just seen a line break in a paragraph, how many closing fences, one per line, make up the rest
+    /// of it; none unless there are fenced divs open here for them all to close.
+    fn closing_fences(&mut self) -> usize {
+        let mut closers = 0;
+        while self.fence_at(closers * 2).as_deref() == Some("") {
+            closers += 1;
+            if let Some(Event::End(Tag::Paragraph)) = self.peek(closers * 2 - 1) {
+                return if closers <= self.fences_here() {
+                    closers
+                } else {
+                    0
+                };
+            }
+        }
+        0
+    }
+
+    /// Having just seen the start of a paragraph beginning with a fence line, open or close a fenced div for
+    /// it, queueing the html, and consume the line.  A malformed fence, or one closing nothing, is reported
+    /// and left as text; then this returns false.
+    fn fence(&mut self, body: &str, offset: usize) -> bool {
+        let line = match &self.lookahead[0].0 {
+            Event::Text(text) => text.trim().to_string(),
+            _ => return false,
+        };
+        if body.is_empty() {
+            if !self.fence_closes_here() {
+                let problem = format!("fence `{}` doesn't close a fenced div", line);
+                self.problems.push((offset, Error::msg(problem)));
+                return false;
+            }
+            self.close_fence();
+        } else {
+            // `:::note wide` is short for `::: {:.note wide}`, which can say anything an annotation can.
+            let parsed = match annotation_body(body, self.options) {
+                Some(annotation) => self.parse_body(annotation, &line),
+                None => self.parse_body(&format!(".{}", body), &line),
+            };
+            let attributes = match parsed {
+                Ok(attributes) => attributes,
+                Err(e) => {
+                    self.problems.push((offset, e));
+                    return false;
+                }
+            };
+            let end_tag = self.end_tag(&attributes);
+            // The paragraph is open, so one less tag is open around the fenced div.
+            self.fences.push((self.open_tags - 1, end_tag, offset));
+            let scope = self.open(attributes);
+            self.scopes.push(scope);
+        }
+        self.lookahead.pop_front();
+        if let Some(Event::SoftBreak) = self.peek(0) {
+            self.lookahead.pop_front();
+        }
+        true
+    }
+
+    /// Queue the closing tag of the innermost fenced div.
+    fn close_fence(&mut self) {
+        if let Some((_, end_tag, _)) = self.fences.pop() {
+            self.scopes.pop();
+            // See the paragraph end below for why the extra event.
+            self.queued.push_back(Event::Html(CowStr::from(end_tag)));
+            self.queued.push_back(Event::End(Tag::Paragraph));
+        }
+    }
+
+    /// Close the innermost fenced div where what holds it ends, or the chapter does, reporting that it was
+    /// never closed.
+    fn abandon_fence(&mut self) {
+        if let Some((_, _, offset)) = self.fences.last() {
+            let error = Error::msg("fenced div isn't closed by a `:::` line");
+            self.problems.push((*offset, error));
+            self.close_fence();
+        }
+    }
+
+    /// Having just seen the start of a heading, turn it into html if it ends with an annotation, as in
+    /// `## Intro {:.big}`: the heading itself gets the attributes, rather than a wrapper.  What the heading
+    /// held is consumed, and its end is left to be dropped.
+    fn annotated_heading(&mut self, level: u32) -> Option<String> {
+        // Headings don't nest, so theirs is the first heading end.
+        let mut end = 0;
+        while !matches!(self.peek(end)?, Event::End(Tag::Heading(_))) {
+            end += 1;
+        }
+        let mut first_text = end;
+        while first_text > 0 && matches!(self.peek(first_text - 1), Some(Event::Text(_))) {
+            first_text -= 1;
+        }
+        let text: String = self
+            .lookahead
+            .range(first_text..end)
+            .filter_map(|(event, _)| match event {
+                Event::Text(text) => Some(text.as_ref()),
+                _ => None,
+            })
+            .collect();
+        let trimmed = text.trim_end();
+        let marker_start = trimmed.rfind(self.options.open_token())?;
+        // An escape ends a text event, so an escaped marker starts one.
+        let mut at = first_text;
+        let mut length = 0;
+        while at < end && length < marker_start {
+            if let Some(Event::Text(text)) = self.peek(at) {
+                length += text.len();
+            }
+            at += 1;
+        }
+        if length == marker_start && self.keep_escaped_marker(at) {
+            return None;
+        }
+        let mut attributes = match self.parse_annotation(&trimmed[marker_start..])? {
+            Ok(attributes) => attributes,
+            Err(e) => {
+                self.problems.push((self.lookahead[0].1.start, e));
+                return None;
+            }
+        };
+        if attributes.tag.is_some()
+            || attributes.title.is_some()
+            || attributes.summary.is_some()
+            || attributes.caption.is_some()
+            || attributes.next
+        {
+            let problem =
+                "sets a tag, title, summary, caption or `next`, which a heading can't have";
+            let e = annotation_error(&trimmed[marker_start..], problem);
+            self.problems.push((self.lookahead[0].1.start, e));
+            return None;
+        }
+        self.opened();
+
+        let mut inline: Vec<Event> = self
+            .lookahead
+            .drain(..end)
+            .map(|(event, _)| event)
+            .take(first_text)
+            .collect();
+        let before = trimmed[..marker_start].trim_end();
+        if !before.is_empty() {
+            inline.push(Event::Text(CowStr::from(before.to_string())));
+        }
+        let mut content = String::new();
+        pulldown_cmark::html::push_html(&mut content, inline.into_iter());
+
+        // Written like mdbook writes the headings it renders, which it only recognizes without attributes.
+        let id = match attributes.id.take() {
+            Some(id) => id,
+            None => {
+                let (source, options) = (self.source, self.options);
+                let ids = self
+                    .heading_ids
+                    .get_or_insert_with(|| plain_heading_ids(source, options));
+                let base = heading_id(&content);
+                let mut id = base.clone();
+                let mut n = 0;
+                while ids.contains(&id) {
+                    n += 1;
+                    id = format!("{}-{}", base, n);
+                }
+                ids.insert(id.clone());
+                id
+            }
+        };
+        attributes.tag = Some(format!("h{}", level));
+        self.add_default_classes(&mut attributes);
+        self.prefix_classes(&mut attributes);
+        Some(format!(
+            "{}<a class=\"header\" href=\"#{id}\" id=\"{id}\">{}</a></h{}>",
+            attributes.start_tag(),
+            content,
+            level,
+            id = escape_html(&id)
+        ))
+    }
+
+    /// Having just seen a paragraph start, consume the annotation that follows it, if there is one.
+    fn take_annotation(&mut self) -> Option<Attributes> {
+        if self.keep_escaped_marker(0) {
+            return None;
+        }
+        let annotation = match self.annotation_at(0) {
+            Some(annotation) => annotation,
+            None => {
+                self.report_near_miss();
+                return None;
+            }
+        };
+        match annotation {
+            Ok(attributes) => Some(attributes),
+            Err(problem) => {
+                self.problems.push(problem);
+                None
+            }
+        }
+    }
+
+    /// With `trailing-markers` on, having just seen a paragraph start, consume the annotation on the
+    /// paragraph's last line, if it ends with one, as kramdown allows.  A malformed one is left in place and
+    /// reported.
+    fn take_trailing_annotation(&mut self) -> Option<Attributes> {
+        if !self.options.trailing_markers {
+            return None;
+        }
+        // Paragraphs don't nest, so the first paragraph end is this one's.
+        let mut end = 0;
+        while !matches!(self.peek(end)?, Event::End(Tag::Paragraph)) {
+            end += 1;
+        }
+        let mut start = end;
+        while start > 0 && matches!(self.peek(start - 1), Some(Event::Text(_))) {
+            start -= 1;
+        }
+        if start == 0
+            || !matches!(self.peek(start - 1), Some(Event::SoftBreak))
+            || self.keep_escaped_marker(start)
+        {
+            return None;
+        }
+        let (line, pieces) = self.marker_at(start)?;
+        if start + pieces != end {
+            return None;
+        }
+        let offset = self.lookahead[start].1.start;
+        match self.parse_annotation(&line)? {
+            Ok(attributes) if attributes.next => {
+                let problem = "has `next`, which only works on an annotation of its own";
+                self.problems
+                    .push((offset, annotation_error(line.trim_end(), problem)));
+                None
+            }
+            Ok(attributes) => {
+                // The line break before the annotation goes with it.
+                self.lookahead.drain(start - 1..end);
+                Some(attributes)
+            }
+            Err(e) => {
+                self.problems.push((offset, e));
+                None
+            }
+        }
+    }
+
+    /// Having just taken the annotation a paragraph starts with, read the rest of the paragraph again if it
+    /// is a table: a table can't interrupt a paragraph, so one written right under its annotation was parsed
+    /// as the paragraph's text.  Returns whether it was, having put the table's events in place of the
+    /// paragraph's, end included.
+    fn reparse_as_table(&mut self) -> bool {
+        let mut end = 0;
+        loop {
+            match self.peek(end) {
+                Some(Event::End(Tag::Paragraph)) => break,
+                Some(_) => end += 1,
+                None => return false,
+            }
+        }
+        let source = self.source;
+        let (start, stop) = (self.lookahead[0].1.start, self.lookahead[end].1.end);
+        if end == 0 || stop > source.len() || !source[start..stop].contains('|') {
+            return false;
+        }
+        // In a blockquote or list item each line starts with the quote markers or indentation the first
+        // one's text does, which have to go for the parser to see the table.
+        let line_start = source[..start].rfind('\n').map_or(0, |newline| newline + 1);
+        let prefix = start - line_start;
+        let mut text = String::with_capacity(stop - start);
+        // Where each of the table's lines starts, in `text` and in the chapter.
+        let mut lines = vec![];
+        let mut at = start;
+        for line in source[start..stop].split_inclusive('\n') {
+            let skipped = if at == start {
+                0
+            } else {
+                line.bytes()
+                    .take(prefix)
+                    .take_while(|&b| b == b' ' || b == b'>')
+                    .count()
+            };
+            lines.push((text.len(), at + skipped));
+            text.push_str(&line[skipped..]);
+            at += line.len();
+        }
+        let in_chapter = |offset: usize| {
+            let (in_text, in_source) = lines
+                .iter()
+                .rev()
+                .find(|(in_text, _)| *in_text <= offset)
+                .copied()
+                .unwrap_or_default();
+            offset - in_text + in_source
+        };
+        let mut table = markdown_parser(&text).into_offset_iter().peekable();
+        if !matches!(table.peek(), Some((Event::Start(Tag::Table(_)), _))) {
+            return false;
+        }
+        let table: Vec<_> = table
+            .map(|(event, range)| {
+                (
+                    owned_event(event),
+                    in_chapter(range.start)..in_chapter(range.end),
+                )
+            })
+            .collect();
+        self.lookahead.drain(..=end);
+        for event in table.into_iter().rev() {
+            self.lookahead.push_front(event);
+        }
+        true
+    }
+
+    /// Note the text just inside a block if it looks like an annotation with a typo in it, to warn about,
+    /// since otherwise nothing says why the block isn't wrapped.
+    fn report_near_miss(&mut self) {
+        if self.options.ignore_near_misses {
+            return;
+        }
+        let line = match self.peek(0) {
+            Some(Event::Text(text)) => text.to_string(),
+            _ => return,
+        };
+        if let Some(problem) = near_miss(&line, self.options) {
+            let offset = self.offset(0).unwrap_or_default();
+            self.near_misses
+                .push((offset, annotation_error(line.trim_end(), &problem)));
+        }
+    }
+
+    /// The closing tag for a block opened with `attributes`.
+    fn end_tag(&self, attributes: &Attributes) -> String {
+        let end_tag = match self.template_for(attributes) {
+            Some(template) => self.fill(&template.close, attributes),
+            None => attributes.end_tag(),
+        };
+        end_tag + &self.debug_comment("close", attributes)
+    }
+
+    /// The template configured for the first of the block's classes that has one.
+    fn template_for(&self, attributes: &Attributes) -> Option<&'o Template> {
+        let templates = &self.options.templates;
+        attributes
+            .classes
+            .iter()
+            .find_map(|class| templates.get(class))
+    }
+
+    /// Fill a template's placeholders in with the block's attributes.
+    fn fill(&self, template: &str, attributes: &Attributes) -> String {
+        let classes: Vec<String> = attributes
+            .classes
+            .iter()
+            .map(|class| self.options.prefixed(class))
+            .collect();
+        template
+            .replace("{class}", &escape_html(&classes.join(" ")))
+            .replace("{id}", &escape_html(attributes.id.as_deref().unwrap_or("")))
+            .replace(
+                "{title}",
+                &escape_html(attributes.title.as_deref().unwrap_or("")),
+            )
+    }
+
+    /// With `debug-markers` on, an html comment tracing a tag back to its annotation; otherwise nothing.
+    fn debug_comment(&self, what: &str, attributes: &Attributes) -> String {
+        if !self.options.debug_markers {
+            return String::new();
+        }
+        format!(
+            "<!-- classy: {} {} -->",
+            what,
+            escape_html(&attributes.describe())
+        )
+    }
+
+    /// Count a block that has just been opened, and whose closing tag is now owed.  Returns how deep it is,
+    /// 1 for a block in no other.
+    fn opened(&mut self) -> usize {
+        self.counts.blocks += 1;
+        self.too_many_blocks |= self.counts.blocks > self.options.max_blocks();
+        let open = self.items.iter().flatten().count()
+            + self.quotes.iter().filter(|&&alert| alert).count()
+            + self.closing.iter().count()
+            + self.wrapped.len()
+            + self.fences.len();
+        self.counts.depth = self.counts.depth.max(open);
+        open
+    }
+
+    /// Follow a block's own classes with the `default-classes` every block gets.
+    fn add_default_classes(&self, attributes: &mut Attributes) {
+        for class in &self.options.default_classes {
+            attributes.add_class(class);
+        }
+    }
+
+    /// Give every class of a block about to be opened the configured prefix, counting it as used.
+    fn prefix_classes(&mut self, attributes: &mut Attributes) {
+        for class in &mut attributes.classes {
+            *class = self.options.prefixed(class);
+            *self.counts.classes.entry(class.clone()).or_insert(0) += 1;
+        }
+    }
+
+    /// Queue the html that opens an annotated block: the start tag, then any summary or title.
+    /// Returns the class that blocks inside it are scoped by, for a block that can have any.
+    fn open(&mut self, mut attributes: Attributes) -> Option<String> {
+        let mut depth = self.opened();
+        if self.options.list_depth {
+            // An annotated item's own entry is already there, so it counts its list like its blocks do.
+            depth = self.items.len();
+        }
+        if self.options.emit_depth_attr {
+            attributes
+                .others
+                .push(("data-depth".to_string(), depth.to_string()));
+        }
+        let comment = self.debug_comment("open", &attributes);
+        let mut scope = attributes.classes.first().cloned();
+        if self.options.inherit_parent_class {
+            if let (Some(Some(parent)), Some(class)) = (self.scopes.last(), &scope) {
+                // A `.body` in a `.card` is also a `.card-body`, and what's in that a `.card-body-...`.
+                let compound = format!("{}-{}", parent, class);
+                attributes.add_class(&compound);
+                scope = Some(compound);
+            }
+        }
+        self.add_default_classes(&mut attributes);
+        if let Some(template) = self.template_for(&attributes) {
+            // The template is the whole opening: no tag, title or admonition of classy's own.
+            for class in &attributes.classes {
+                *self
+                    .counts
+                    .classes
+                    .entry(self.options.prefixed(class))
+                    .or_insert(0) += 1;
+            }
+            let opening = self.fill(&template.open, &attributes) + &comment;
+            self.queued.push_back(Event::Html(CowStr::from(opening)));
+            return scope;
+        }
+        let mut opening = vec![];
+        if let Some(summary) = attributes.summary_element() {
+            opening.push(summary);
+        }
+        if let (true, Some(id)) = (self.options.block_anchors, &attributes.id) {
+            // Like the links mdbook gives headings, and after the summary, which has to come first.
+            opening.push(format!(
+                "<a class=\"{}\" href=\"#{}\">#</a>",
+                escape_html(&self.options.prefixed("block-anchor")),
+                escape_html(id)
+            ));
+        }
+        if let Some(admonition) = self.admonition_for(&attributes) {
+            // An explicit title beats the preset's.
+            let title_text = attributes
+                .title
+                .take()
+                .unwrap_or_else(|| admonition.title.clone());
+            opening.push(format!(
+                "<p class=\"{}\">{}</p>",
+                escape_html(&self.options.prefixed("admonition-title")),
+                escape_html(&title_text)
+            ));
+            attributes.classes.retain(|class| class != "admonition");
+            attributes.classes.insert(0, "admonition".to_string());
+            if self.options.aria {
+                attributes.add_aria(&admonition.role, &title_text);
+            }
+        } else if let Some(title) = &attributes.title {
+            opening.push(format!(
+                "<div class=\"{}\">{}</div>",
+                escape_html(&self.options.prefixed("classy-title")),
+                escape_html(title)
+            ));
+        }
+        self.prefix_classes(&mut attributes);
+        opening.insert(0, attributes.start_tag() + &comment);
+        self.queued.extend(
+            opening
+                .into_iter()
+                .map(|html| Event::Html(CowStr::from(html))),
+        );
+        scope
+    }
+}
+
+impl<'a, 'o, I: Iterator<Item = (Event<'a>, Range<usize>)>> Iterator for ClassAnnotator<'a, 'o, I> {
+    type Item = Event<'a>;
+
+    fn next(&mut self) -> Option<Event<'a>> {
+        // Past `max-blocks` nothing that follows will be used, so don't spend any more on it.
+        if self.too_many_blocks {
+            return None;
+        }
+        if let Some(event) = self.queued.pop_front() {
+            return Some(event);
+        }
+        let (event, range) = match self.pull() {
+            Some(next) => next,
+            None => {
+                self.abandon_pending();
+                self.abandon_fence();
+                while let Some((_, end_tag)) = self.wrapped.pop() {
+                    self.scopes.pop();
+                    self.queued.push_back(Event::Html(CowStr::from(end_tag)));
+                    self.queued.push_back(Event::End(Tag::Paragraph));
+                }
+                return self.queued.pop_front();
+            }
+        };
+        if self.item_text && ends_inline_content(&event) {
+            self.item_text = false;
+            self.lookahead.push_front((event, range));
+            return Some(Event::End(Tag::Paragraph));
+        }
+        if let Event::End(_) = event {
+            if matches!(self.fences.last(), Some((open_tags, ..)) if *open_tags == self.open_tags) {
+                // What holds the fenced div ends before its closing fence: close it first.
+                self.lookahead.push_front((event, range));
+                self.abandon_fence();
+                return self.queued.pop_front();
+            }
+        }
+        if let Event::Start(_) = event {
+            if let Some((attributes, _)) = self.pending.take() {
+                // The block the annotation applies to: open it, and put the block's start back to be
+                // handled as usual once the opening html is out.
+                let end_tag = self.end_tag(&attributes);
+                self.wrapped.push((self.open_tags, end_tag));
+                let scope = self.open(attributes);
+                self.scopes.push(scope);
+                self.lookahead.push_front((event, range));
+                return self.queued.pop_front();
+            }
+        } else {
+            self.abandon_pending();
+        }
+
+        let mut closing = vec![];
+        match event {
+            Event::Start(_) => self.open_tags += 1,
+            Event::End(_) => {
+                self.open_tags -= 1;
+                // Stacked `next` annotations all wrap the same block, so all end with it, innermost first.
+                while matches!(self.wrapped.last(), Some((open_tags, _)) if *open_tags == self.open_tags)
+                {
+                    closing.extend(self.wrapped.pop().map(|(_, end_tag)| end_tag));
+                    self.scopes.pop();
+                }
+            }
+            _ => {}
+        }
+        let next = self.process(event);
+        for end_tag in closing {
+            // After anything the block's own end brought, and spaced like a paragraph's closing tag.
+            self.queued.push_back(Event::Html(CowStr::from(end_tag)));
+            self.queued.push_back(Event::End(Tag::Paragraph));
+        }
+        next
+    }
+}
+
+impl<'a, 'o, I: Iterator<Item = (Event<'a>, Range<usize>)>> ClassAnnotator<'a, 'o, I> {
+    /// Transform the next event, returning the first event out and queueing the rest.
+    fn process(&mut self, event: Event<'a>) -> Option<Event<'a>> {
+        match event {
+            Event::Start(Tag::BlockQuote) => {
+                let alert = self.take_alert();
+                self.quotes.push(alert.is_some());
+                if let Some(kind) = alert {
+                    self.opened();
+                    let mut attributes = Attributes {
+                        classes: vec!["alert".to_string(), format!("alert-{}", kind)],
+                        ..Attributes::default()
+                    };
+                    if self.options.aria {
+                        attributes.add_aria(default_role(kind), &capitalize(kind));
+                    }
+                    self.add_default_classes(&mut attributes);
+                    self.prefix_classes(&mut attributes);
+                    self.queued.push_back(event);
+                    return Some(Event::Html(CowStr::from(attributes.start_tag())));
+                }
+            }
+            Event::End(Tag::BlockQuote) => {
+                let was_alert = self.quotes.pop() == Some(true);
+                if was_alert {
+                    // See the paragraph end below for why the extra event.
+                    self.queued.push_back(Event::Html(CowStr::from("</div>")));
+                    self.queued.push_back(Event::End(Tag::Paragraph));
+                }
+            }
+            Event::Start(Tag::Heading(level)) => {
+                if let Some(heading) = self.annotated_heading(level) {
+                    self.heading_end = true;
+                    return Some(Event::Html(CowStr::from(heading)));
+                }
+            }
+            Event::End(Tag::Heading(_)) if self.heading_end => {
+                // Spaces the html from what follows, like the end of an annotated paragraph.
+                self.heading_end = false;
+                return Some(Event::End(Tag::Paragraph));
+            }
+            Event::Start(Tag::Paragraph) => {
+                let offset = self.offset(0);
+                let mut fenced = false;
+                while let Some(body) = self.fence_at(0) {
+                    let offset = self.offset(0).unwrap_or_default();
+                    if !self.fence(&body, offset) {
+                        break;
+                    }
+                    fenced = true;
+                }
+                if fenced {
+                    if let Some(Event::End(Tag::Paragraph)) = self.peek(0) {
+                        // The fences were the whole paragraph, which goes.
+                        self.pull();
+                        self.open_tags -= 1;
+                        return self.queued.pop_front().or_else(|| self.next());
+                    }
+                    self.queued.push_back(event);
+                    return self.queued.pop_front();
+                }
+                if self.merging {
+                    // Its annotation was merged into the wrapper it's already in.
+                    self.merging = false;
+                    self.take_annotation();
+                    return Some(event);
+                }
+                let attributes = match self.take_annotation() {
+                    Some(attributes) => Some(attributes),
+                    None => self.take_trailing_annotation(),
+                };
+                if let Some(mut attributes) = attributes {
+                    if attributes.next && matches!(self.peek(0), Some(Event::End(Tag::Paragraph))) {
+                        // The annotation was the whole paragraph, which goes; the block after it is wrapped.
+                        self.pull();
+                        self.open_tags -= 1;
+                        self.pending = Some((attributes, offset.unwrap_or_default()));
+                        return self.next();
+                    }
+                    if self.reparse_as_table() {
+                        // What followed the annotation is a table now, and the paragraph is gone.
+                        self.open_tags -= 1;
+                        self.pending = Some((attributes, offset.unwrap_or_default()));
+                        return self.next();
+                    }
+                    if self.options.merge_adjacent {
+                        self.merge_following(&mut attributes);
+                    }
+                    self.closing = Some(self.end_tag(&attributes));
+                    self.open(attributes);
+                    self.queued.push_back(event);
+                    return self.queued.pop_front();
+                }
+            }
+            Event::Start(Tag::Item) => {
+                // The annotation leads either the item's text (in a tight list) or its first paragraph (in a
+                // loose one).  A malformed one in a paragraph is left for the paragraph to report.
+                let loose = matches!(self.peek(0), Some(Event::Start(Tag::Paragraph)));
+                let attributes = if loose {
+                    self.annotation_at(1).and_then(Result::ok)
+                } else {
+                    self.take_annotation()
+                };
+                let end_tag = attributes
+                    .as_ref()
+                    .map(|attributes| self.end_tag(attributes));
+                self.items.push(end_tag);
+                if let Some(attributes) = attributes {
+                    self.queued.push_back(event);
+                    let scope = self.open(attributes);
+                    self.scopes.push(scope);
+                    if !loose {
+                        // Html has to stand apart from markdown, so the text gets a paragraph of its own.
+                        self.queued.push_back(Event::Start(Tag::Paragraph));
+                        self.item_text = true;
+                    } else if matches!(self.peek(1), Some(Event::End(Tag::Paragraph))) {
+                        // The annotation was the whole paragraph, so drop the paragraph too.
+                        self.lookahead.drain(0..2);
+                    }
+                    return self.queued.pop_front();
+                }
+            }
+            Event::End(Tag::Item) => {
+                if let Some(end_tag) = self.items.pop().flatten() {
+                    self.scopes.pop();
+                    // See the paragraph end below for why the extra event.
+                    self.queued.push_back(Event::End(Tag::Paragraph));
+                    self.queued.push_back(event);
+                    return Some(Event::Html(CowStr::from(end_tag)));
+                }
+            }
+            Event::SoftBreak => {
+                let closers = self.closing_fences();
+                if closers > 0 {
+                    // Closing fences as the paragraph's last lines: the fenced divs close after the paragraph.
+                    self.lookahead.drain(..closers * 2 - 1);
+                    let mut closing = self.closing.take().unwrap_or_default();
+                    for _ in 0..closers {
+                        if let Some((_, end_tag, _)) = self.fences.pop() {
+                            self.scopes.pop();
+                            closing += &end_tag;
+                        }
+                    }
+                    self.closing = Some(closing);
+                    return self.next();
+                }
+            }
+            Event::End(Tag::Paragraph) if self.merges > 0 && self.closing.is_some() => {
+                // The next paragraph shares the wrapper, so it stays open.
+                self.merges -= 1;
+                self.merging = true;
+            }
+            Event::End(Tag::Paragraph) => {
+                if let Some(end_tag) = self.closing.take() {
+                    // Ending the paragraph a second time after the closing tag makes the serializer leave
+                    // a blank line there; without it the following markdown would be swallowed into the
+                    // html block.
+                    self.queued.push_back(Event::Html(CowStr::from(end_tag)));
+                    self.queued.push_back(Event::End(Tag::Paragraph));
+                }
+            }
+            _ => {}
+        }
+        Some(event)
+    }
+}
+
+/// Whether `event` is the start or end of a block, or a rule, rather than part of a run of inline content.
+fn ends_inline_content(event: &Event) -> bool {
+    match event {
+        Event::Start(tag) | Event::End(tag) => !matches!(
+            tag,
+            Tag::Emphasis | Tag::Strong | Tag::Strikethrough | Tag::Link(..) | Tag::Image(..)
+        ),
+        Event::Rule => true,
+        _ => false,
+    }
+}
+
+/// The content of every chapter in `items` and below, in book order, named the way an author would look
+/// for it: by its source path, or its title for drafts.  Each comes with how deep it is in the table of
+/// contents, counting the chapters in `items` as `level`.
+/// Drafts have no file to hold anything but what other preprocessors put there, so they are left out
+/// unless `drafts` is set; the chapters under them aren't.
+fn collect_chapters<'b>(
+    items: &'b mut [BookItem],
+    level: usize,
+    drafts: bool,
+    chapters: &mut Vec<(String, usize, &'b mut String)>,
+) {
+    for item in items {
+        if let BookItem::Chapter(chapter) = item {
+            let location = match &chapter.path {
+                Some(path) => Some(path.display().to_string()),
+                None if drafts => Some(chapter.name.clone()),
+                None => None,
+            };
+            if let Some(location) = location {
+                chapters.push((location, level, &mut chapter.content));
+            }
+            collect_chapters(&mut chapter.sub_items, level + 1, drafts, chapters);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn annotated(events: Vec<Event<'static>>) -> (Vec<Event<'static>>, Vec<(usize, Error)>) {
+        annotated_with(events, &Options::default())
+    }
+
+    fn annotated_with(
+        events: Vec<Event<'static>>,
+        options: &Options,
+    ) -> (Vec<Event<'static>>, Vec<(usize, Error)>) {
+        // The events don't come from a chapter, so they have no place in one either.
+        let events = events.into_iter().map(|event| (event, 0..0));
+        let mut annotator = ClassAnnotator::new(events, "", options);
+        let output = annotator.by_ref().collect();
+        (output, annotator.problems)
+    }
+
+    fn options(config: &str) -> Options {
+        let config: toml::value::Table = toml::from_str(config).unwrap();
+        Options::new(Config::from_table(&config).unwrap()).unwrap()
+    }
+
+    fn html(html: &'static str) -> Event<'static> {
+        Event::Html(CowStr::Borrowed(html))
+    }
+
+    fn text(text: &'static str) -> Event<'static> {
+        Event::Text(CowStr::Borrowed(text))
+    }
+
+    #[test]
+    fn wraps_an_annotated_paragraph() {
+        let (output, problems) = annotated(vec![
+            Event::Start(Tag::Paragraph),
+            text("{:.note}"),
+            Event::SoftBreak,
+            text("Remember this."),
+            Event::End(Tag::Paragraph),
+        ]);
+        assert!(problems.is_empty());
+        assert_eq!(
+            output,
+            vec![
+                html("<div class=\"note\">"),
+                Event::Start(Tag::Paragraph),
+                text("Remember this."),
+                Event::End(Tag::Paragraph),
+                html("</div>"),
+                Event::End(Tag::Paragraph),
+            ]
+        );
+    }
+
+    #[test]
+    fn wraps_a_paragraph_nested_in_a_blockquote() {
+        let (output, _) = annotated(vec![
+            Event::Start(Tag::BlockQuote),
+            Event::Start(Tag::Paragraph),
+            text("{:#intro.note .small}"),
+            text("Quoted."),
+            Event::End(Tag::Paragraph),
+            Event::End(Tag::BlockQuote),
+        ]);
+        assert_eq!(
+            output,
+            vec![
+                Event::Start(Tag::BlockQuote),
+                html("<div id=\"intro\" class=\"note small\">"),
+                Event::Start(Tag::Paragraph),
+                text("Quoted."),
+                Event::End(Tag::Paragraph),
+                html("</div>"),
+                Event::End(Tag::Paragraph),
+                Event::End(Tag::BlockQuote),
+            ]
+        );
+    }
+
+    #[test]
+    fn wraps_a_tight_list_item() {
+        let (output, problems) = annotated(vec![
+            Event::Start(Tag::List(None)),
+            Event::Start(Tag::Item),
+            text("{:.done}"),
+            Event::SoftBreak,
+            text("Shipped."),
+            Event::Start(Tag::List(None)),
+            Event::Start(Tag::Item),
+            text("Nested."),
+            Event::End(Tag::Item),
+            Event::End(Tag::List(None)),
+            Event::End(Tag::Item),
+            Event::End(Tag::List(None)),
+        ]);
+        assert!(problems.is_empty());
+        assert_eq!(
+            output,
+            vec![
+                Event::Start(Tag::List(None)),
+                Event::Start(Tag::Item),
+                html("<div class=\"done\">"),
+                Event::Start(Tag::Paragraph),
+                text("Shipped."),
+                Event::End(Tag::Paragraph),
+                Event::Start(Tag::List(None)),
+                Event::Start(Tag::Item),
+                text("Nested."),
+                Event::End(Tag::Item),
+                Event::End(Tag::List(None)),
+                html("</div>"),
+                Event::End(Tag::Paragraph),
+                Event::End(Tag::Item),
+                Event::End(Tag::List(None)),
+            ]
+        );
+    }
+
+    #[test]
+    fn wraps_a_loose_list_item() {
+        let (output, problems) = annotated(vec![
+            Event::Start(Tag::List(Some(1))),
+            Event::Start(Tag::Item),
+            Event::Start(Tag::Paragraph),
+            text("{:.step}"),
+            Event::End(Tag::Paragraph),
+            Event::Start(Tag::Paragraph),
+            text("First."),
+            Event::End(Tag::Paragraph),
+            Event::End(Tag::Item),
+            Event::End(Tag::List(Some(1))),
+        ]);
+        assert!(problems.is_empty());
+        assert_eq!(
+            output,
+            vec![
+                Event::Start(Tag::List(Some(1))),
+                Event::Start(Tag::Item),
+                html("<div class=\"step\">"),
+                Event::Start(Tag::Paragraph),
+                text("First."),
+                Event::End(Tag::Paragraph),
+                html("</div>"),
+                Event::End(Tag::Paragraph),
+                Event::End(Tag::Item),
+                Event::End(Tag::List(Some(1))),
+            ]
+        );
+    }
+
+    #[test]
+    fn closes_every_wrapper_of_stacked_next_annotations() {
+        let (output, problems) = annotated(vec![
+            Event::Start(Tag::Paragraph),
+            text("{:.a next}"),
+            Event::End(Tag::Paragraph),
+            Event::Start(Tag::Paragraph),
+            text("{:.b next}"),
+            Event::End(Tag::Paragraph),
+            Event::Start(Tag::Paragraph),
+            text("Para."),
+            Event::End(Tag::Paragraph),
+        ]);
+        assert!(problems.is_empty());
+        assert_eq!(
+            output,
+            vec![
+                html("<div class=\"a\">"),
+                html("<div class=\"b\">"),
+                Event::Start(Tag::Paragraph),
+                text("Para."),
+                Event::End(Tag::Paragraph),
+                html("</div>"),
+                Event::End(Tag::Paragraph),
+                html("</div>"),
+                Event::End(Tag::Paragraph),
+            ]
+        );
+    }
+
+    #[test]
+    fn wraps_the_block_after_a_next_annotation() {
+        let (output, problems) = annotated(vec![
+            Event::Start(Tag::Paragraph),
+            text("{:.highlight next}"),
+            Event::End(Tag::Paragraph),
+            Event::Start(Tag::List(None)),
+            Event::Start(Tag::Item),
+            text("One."),
+            Event::End(Tag::Item),
+            Event::End(Tag::List(None)),
+            Event::Start(Tag::Paragraph),
+            text("After."),
+            Event::End(Tag::Paragraph),
+        ]);
+        assert!(problems.is_empty());
+        assert_eq!(
+            output,
+            vec![
+                html("<div class=\"highlight\">"),
+                Event::Start(Tag::List(None)),
+                Event::Start(Tag::Item),
+                text("One."),
+                Event::End(Tag::Item),
+                Event::End(Tag::List(None)),
+                html("</div>"),
+                Event::End(Tag::Paragraph),
+                Event::Start(Tag::Paragraph),
+                text("After."),
+                Event::End(Tag::Paragraph),
+            ]
+        );
+
+        let (_, problems) = annotated(vec![
+            Event::Start(Tag::Paragraph),
+            text("{:.highlight next}"),
+            Event::End(Tag::Paragraph),
+        ]);
+        assert_eq!(
+            problems[0].1.to_string(),
+            "class annotation with `next` isn't followed by a block it can wrap"
+        );
+    }
+
+    #[test]
+    fn reports_next_annotations_that_share_their_paragraph() {
+        for content in &[
+            "{:.highlight next}\nText.\n\n- one\n",
+            "- {:.highlight next}\n  One.\n- Two.\n",
+        ] {
+            let transformed = transform(content, &Options::default());
+            assert_eq!(transformed.content, *content);
+            let problems: Vec<(usize, String)> = transformed
+                .problems
+                .iter()
+                .map(|problem| (problem.line, problem.error.to_string()))
+                .collect();
+            assert_eq!(
+                problems,
+                vec![(
+                    1,
+                    "class annotation `{:.highlight next}` has `next`, which only works on an annotation \
+                     of its own"
+                        .to_string()
+                )]
+            );
+        }
+    }
+
+    #[test]
+    fn leaves_unannotated_paragraphs_alone() {
+        let input = vec![
+            Event::Start(Tag::Paragraph),
+            text("Just text {:.note}"),
+            Event::End(Tag::Paragraph),
+        ];
+        let (output, problems) = annotated(input.clone());
+        assert!(problems.is_empty());
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn leaves_chapters_without_markers_untouched() {
+        let content = "Some   *text*\n\n- a list\n+ another\n\n> [!NOTE]\n> not an alert\n";
+        let transformed = transform(content, &Options::default());
+        assert!(transformed.problems.is_empty());
+        assert_eq!(transformed.content, content);
+    }
+
+    #[test]
+    fn leaves_chapters_whose_markers_change_nothing_untouched() {
+        let content = "Use `{:.note}` for notes __like__ [this][ref].\n\n\
+                       1) first\n2) second\n\n\
+                       ```\n{:.in-code}\n```\n\n\
+                       Not at the start {:.note}\n\n\
+                       [ref]: https://example.com\n";
+        let transformed = transform(content, &Options::default());
+        assert!(transformed.problems.is_empty());
+        assert_eq!(transformed.content, content);
+    }
+
+    #[test]
+    fn annotates_text_the_events_own() {
+        use pulldown_cmark::InlineStr;
+        use std::convert::TryFrom;
+
+        let boxed = |text: &str| Event::Text(CowStr::Boxed(text.to_string().into_boxed_str()));
+        let inlined = |text: &str| Event::Text(CowStr::Inlined(InlineStr::try_from(text).unwrap()));
+        for annotation in &[
+            vec![boxed("{:.note}")],
+            vec![inlined("{:.note}")],
+            vec![inlined("{:"), boxed(".note}")],
+        ] {
+            let mut input = vec![Event::Start(Tag::Paragraph)];
+            input.extend(annotation.iter().cloned());
+            input.extend(vec![
+                Event::SoftBreak,
+                boxed("Owned text."),
+                Event::End(Tag::Paragraph),
+            ]);
+            let (output, problems) = annotated(input);
+            assert!(problems.is_empty());
+            assert_eq!(
+                output,
+                vec![
+                    html("<div class=\"note\">"),
+                    Event::Start(Tag::Paragraph),
+                    text("Owned text."),
+                    Event::End(Tag::Paragraph),
+                    html("</div>"),
+                    Event::End(Tag::Paragraph),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn reports_an_empty_id() {
+        for annotation in &["{:#}", "{:.a #}", "{:#.a}", "{:.a id=\"\"}"] {
+            let input = vec![
+                Event::Start(Tag::Paragraph),
+                Event::Text(CowStr::Borrowed(annotation)),
+                Event::End(Tag::Paragraph),
+            ];
+            let (output, problems) = annotated(input.clone());
+            assert_eq!(output, input);
+            assert_eq!(
+                problems[0].1.to_string(),
+                format!("class annotation `{}` has an empty id", annotation)
+            );
+        }
+    }
+
+    #[test]
+    fn reports_a_malformed_annotation_and_leaves_it_in_place() {
+        let input = vec![
+            Event::Start(Tag::Paragraph),
+            text("{:#one #two}"),
+            Event::End(Tag::Paragraph),
+        ];
+        let (output, problems) = annotated(input.clone());
+        assert_eq!(output, input);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(
+            problems[0].1.to_string(),
+            "class annotation `{:#one #two}` sets more than one id"
+        );
+    }
+
+    #[test]
+    fn reports_the_line_of_a_malformed_annotation() {
+        let mut content = "# Title\n\n- item\n\n  {:#one #two}\n  text\n".to_string();
+        let (_, problems) = classy(&mut content, "guide.md", 1, &Options::default()).unwrap();
+        assert_eq!(
+            problems,
+            vec!["guide.md:5: class annotation `{:#one #two}` sets more than one id"]
+        );
+    }
+
+    #[test]
+    fn handles_text_runs_shorter_than_an_annotation() {
+        let input = vec![
+            Event::Start(Tag::Paragraph),
+            text("a"),
+            Event::End(Tag::Paragraph),
+            Event::Start(Tag::Paragraph),
+            text("{"),
+            Event::End(Tag::Paragraph),
+        ];
+        let (output, problems) = annotated(input.clone());
+        assert!(problems.is_empty());
+        assert_eq!(output, input);
+
+        let (output, _) = annotated(vec![
+            Event::Start(Tag::Paragraph),
+            text("{:.a}"),
+            Event::End(Tag::Paragraph),
+        ]);
+        assert_eq!(output[0], html("<div class=\"a\">"));
+    }
+
+    #[test]
+    fn handles_annotations_of_any_length() {
+        for (annotation, start_tag) in &[
+            ("{:.}", "<div>"),
+            ("{:.a}", "<div class=\"a\">"),
+            ("{:.é}", "<div class=\"é\">"),
+            ("{:.noté}", "<div class=\"noté\">"),
+            ("{:.注意 #警告}", "<div id=\"警告\" class=\"注意\">"),
+        ] {
+            let (output, problems) = annotated(vec![
+                Event::Start(Tag::Paragraph),
+                text(annotation),
+                text("Short."),
+                Event::End(Tag::Paragraph),
+            ]);
+            assert!(problems.is_empty());
+            assert_eq!(output[0], html(start_tag), "for {}", annotation);
+            assert_eq!(output[2], text("Short."), "for {}", annotation);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_class_names_unless_told_not_to() {
+        let input = vec![
+            Event::Start(Tag::Paragraph),
+            text("{:.my class!}"),
+            Event::End(Tag::Paragraph),
+            Event::Start(Tag::Paragraph),
+            text("{:.ok class=\"foo;bar\"}"),
+            Event::End(Tag::Paragraph),
+        ];
+        let (output, problems) = annotated(input.clone());
+        assert_eq!(output, input);
+        assert_eq!(
+            problems[0].1.to_string(),
+            "class annotation `{:.my class!}` has an invalid class name `class!`"
+        );
+        assert_eq!(
+            problems[1].1.to_string(),
+            "class annotation `{:.ok class=\"foo;bar\"}` has an invalid class name `foo;bar`"
+        );
+
+        let options = options("any-class-names = true");
+        let (output, problems) = annotated_with(input, &options);
+        assert!(problems.is_empty());
+        assert_eq!(output[0], html("<div class=\"my class!\">"));
+    }
+
+    #[test]
+    fn only_allows_the_allowed_classes() {
+        let options = options("allowed-classes = [\"note\", \"figure\"]");
+        let (output, problems) = annotated_with(
+            vec![
+                Event::Start(Tag::Paragraph),
+                text("{:.note .figure}"),
+                Event::End(Tag::Paragraph),
+                Event::Start(Tag::Paragraph),
+                text("{:.note .fancy}"),
+                Event::End(Tag::Paragraph),
+            ],
+            &options,
+        );
+        assert_eq!(output[0], html("<div class=\"note figure\">"));
+        assert_eq!(problems.len(), 1);
+        assert_eq!(
+            problems[0].1.to_string(),
+            "class annotation `{:.note .fancy}` uses `fancy`, which isn't an allowed class"
+        );
+    }
+
+    #[test]
+    fn expands_aliases() {
+        let options = options(
+            "allowed-classes = [\"warn\", \"note\"]\n\
+             [aliases]\nwarn = \"admonition warning important\"\nwar = \"nothing\"\n",
+        );
+        let (output, problems) = annotated_with(
+            vec![
+                Event::Start(Tag::Paragraph),
+                text("{:.note .warn}"),
+                Event::End(Tag::Paragraph),
+            ],
+            &options,
+        );
+        assert!(problems.is_empty());
+        assert_eq!(
+            output[0],
+            html("<div class=\"note warn admonition warning important\">")
+        );
+    }
+
+    #[test]
+    fn keeps_frontmatter_as_it_is() {
+        let content = "---\ntitle: Intro\ntags: [a, b]\n---\n\n{:.note}\nHi.\n\n{:#a #b}\nNo.\n";
+        let transformed = transform(content, &Options::default());
+        assert!(transformed
+            .content
+            .starts_with("---\ntitle: Intro\ntags: [a, b]\n---\n\n<div class=\"note\">"));
+        assert_eq!(transformed.problems[0].line, 9);
+
+        // A rule followed by a blank line is just a rule, however many more follow.
+        assert_eq!(split_frontmatter("---\n\nText\n---\n").0, "");
+        assert_eq!(split_frontmatter("---\ntitle: Unclosed\n").0, "");
+    }
+
+    #[test]
+    fn ignores_whitespace_after_an_annotation() {
+        let options = Options::default();
+        assert_eq!(annotation_body("{:.note}\r", &options), Some(".note"));
+        assert_eq!(annotation_body("{:.note} ", &options), Some(".note"));
+        let crlf = transform("{:.note}\r\nLine.\r\n", &Options::default());
+        let lf = transform("{:.note}\nLine.\n", &Options::default());
+        assert_eq!(crlf.content, lf.content);
+    }
+
+    #[test]
+    fn closes_the_tag_it_opened() {
+        let (output, _) = annotated(vec![
+            Event::Start(Tag::Paragraph),
+            text("{:.sidebar tag=aside}"),
+            Event::End(Tag::Paragraph),
+        ]);
+        assert_eq!(output[0], html("<aside class=\"sidebar\">"));
+        assert_eq!(output[3], html("</aside>"));
+    }
+
+    #[test]
+    fn prefixes_every_emitted_class() {
+        let options = options("class-prefix = \"mdbook-\"\n[admonitions]\n");
+        let (output, _) = annotated_with(
+            vec![
+                Event::Start(Tag::Paragraph),
+                text("{:.note .small}"),
+                Event::End(Tag::Paragraph),
+                Event::Start(Tag::Paragraph),
+                text("{:.figure title=Diagram}"),
+                Event::End(Tag::Paragraph),
+            ],
+            &options,
+        );
+        assert_eq!(
+            output[0],
+            html("<div class=\"mdbook-admonition mdbook-note mdbook-small\">")
+        );
+        assert_eq!(
+            output[1],
+            html("<p class=\"mdbook-admonition-title\">Note</p>")
+        );
+        assert_eq!(output[6], html("<div class=\"mdbook-figure\">"));
+        assert_eq!(
+            output[7],
+            html("<div class=\"mdbook-classy-title\">Diagram</div>")
+        );
+        assert!(Options::new(
+            Config::from_table(&toml::from_str("class-prefix = \"a b\"").unwrap()).unwrap()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn fails_on_problems_only_in_strict_mode() {
+        let book = || {
+            let mut book = Book::new();
+            let content = "{:#one #two}\nBad.\n\n{:.fine}\nGood.\n".to_string();
+            book.push_item(mdbook::book::Chapter::new("One", content, "one.md", vec![]));
+            book
+        };
+        let config = |classy: &str| -> BookConfig {
+            format!("[preprocessor.classy]\n{}", classy)
+                .parse()
+                .unwrap()
+        };
+
+        let mut lenient = book();
+        Classy.annotate_book(&config(""), &mut lenient).unwrap();
+        match &lenient.sections[0] {
+            BookItem::Chapter(chapter) => assert!(chapter.content.contains("<div class=\"fine\">")),
+            _ => unreachable!(),
+        }
+
+        let error = Classy
+            .annotate_book(&config("strict = true"), &mut book())
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "one.md:1: class annotation `{:#one #two}` sets more than one id"
+        );
+        assert!(Classy.check_book(&config(""), &mut book()).is_err());
+    }
+
+    #[test]
+    fn recognizes_configured_tokens() {
+        let options = options("open-token = \"%%\"\nclose-token = \"%%\"\n");
+        let (output, problems) = annotated_with(
+            vec![
+                Event::Start(Tag::Paragraph),
+                text("{:.ignored}"),
+                Event::End(Tag::Paragraph),
+                Event::Start(Tag::Paragraph),
+                text("%% .note %%"),
+                Event::End(Tag::Paragraph),
+                Event::Start(Tag::Paragraph),
+                text("%%#a #b%%"),
+                Event::End(Tag::Paragraph),
+            ],
+            &options,
+        );
+        assert_eq!(output[1], text("{:.ignored}"));
+        assert_eq!(output[3], html("<div class=\"note\">"));
+        assert_eq!(
+            problems[0].1.to_string(),
+            "class annotation `%%#a #b%%` sets more than one id"
+        );
+    }
+
+    #[test]
+    fn reads_settings_and_tolerates_unknown_keys() {
+        let table = |config: &str| -> toml::value::Table { toml::from_str(config).unwrap() };
+        let config = Config::from_table(&table(
+            "command = \"x\"\nstrcit = true\nclass-prefix = \"b-\"",
+        ))
+        .unwrap();
+        assert_eq!(config.class_prefix, "b-");
+        assert!(!config.strict);
+
+        let error = Config::from_table(&table("strict = \"yes\"")).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("invalid [preprocessor.classy] settings: "),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn traces_tags_to_annotations_in_debug_mode() {
+        let options = options("debug-markers = true\nclass-prefix = \"x-\"");
+        let (output, _) = annotated_with(
+            vec![
+                Event::Start(Tag::Paragraph),
+                text("{:#intro.note .small}"),
+                text("Hi."),
+                Event::End(Tag::Paragraph),
+            ],
+            &options,
+        );
+        assert_eq!(
+            output[0],
+            html("<div id=\"intro\" class=\"x-note x-small\"><!-- classy: open note small #intro -->")
+        );
+        assert_eq!(
+            output[4],
+            html("</div><!-- classy: close note small #intro -->")
+        );
+    }
+
+    #[test]
+    fn supports_the_configured_renderers() {
+        let config = |config: &str| Config::from_table(&toml::from_str(config).unwrap()).unwrap();
+        assert!(config("").supports_renderer("epub"));
+        assert!(!config("").supports_renderer("pdf"));
+        assert!(config("supported-renderers = true").supports_renderer("pdf"));
+        assert!(config("supported-renderers = [\"*\"]").supports_renderer("pdf"));
+        let only_pdf = config("supported-renderers = [\"pdf\"]");
+        assert!(only_pdf.supports_renderer("pdf"));
+        assert!(!only_pdf.supports_renderer("html"));
+    }
+
+    #[test]
+    fn installs_the_preprocessor_table_once() {
+        assert_eq!(
+            install_table("[book]\ntitle = \"Guide\"", "classy")
+                .unwrap()
+                .as_deref(),
+            Some("[book]\ntitle = \"Guide\"\n\n[preprocessor.classy]\n")
+        );
+        let installed = "[book]\ntitle = \"Guide\"\n\n[preprocessor.classy]\naria = true\n";
+        assert_eq!(install_table(installed, "classy").unwrap(), None);
+        let commented = "# The guide.\n[book]\ntitle = \"Guide\" # for now\n";
+        assert_eq!(
+            install_table(commented, "classy").unwrap().as_deref(),
+            Some("# The guide.\n[book]\ntitle = \"Guide\" # for now\n\n[preprocessor.classy]\n")
+        );
+        assert!(install_table("[book", "classy").is_err());
+    }
+
+    #[test]
+    fn supports_the_renderers_the_book_configures() {
+        let book = |config: &str| -> BookConfig { config.parse().unwrap() };
+        let defaults = book("");
+        assert!(Classy.supports_renderer_in(&defaults, "html"));
+        assert!(Classy.supports_renderer_in(&defaults, "epub"));
+        assert!(!Classy.supports_renderer_in(&defaults, "pdf"));
+
+        let only_pdf = book("[preprocessor.classy]\nsupported-renderers = [\"pdf\"]");
+        assert!(!Classy.supports_renderer_in(&only_pdf, "html"));
+        assert!(!Classy.supports_renderer_in(&only_pdf, "epub"));
+        assert!(Classy.supports_renderer_in(&only_pdf, "pdf"));
+
+        let unreadable = book("[preprocessor.classy]\nsupported-renderers = 3");
+        assert!(Classy.supports_renderer_in(&unreadable, "html"));
+        assert!(!Classy.supports_renderer_in(&unreadable, "pdf"));
+    }
+
+    #[test]
+    fn scopes_nested_classes_by_their_parents() {
+        let content = "{:.card next}\n\n> {:.body}\n> Text.\n\n\
+                       * {:.card}\n  Card.\n\n  * {:.body}\n    Body.\n\n    * {:.title .big}\n      Title.\n\n\
+                       {:.alone}\nOut.\n";
+        let inherited = transform(content, &options("inherit-parent-class = true")).content;
+        assert!(inherited.contains("<div class=\"body card-body\">"));
+        assert!(inherited.contains("<div class=\"title big card-body-title\">"));
+        assert!(inherited.contains("<div class=\"alone\">"));
+
+        let plain = transform(content, &Options::default()).content;
+        assert!(plain.contains("<div class=\"title big\">"));
+    }
+
+    #[test]
+    fn wraps_in_the_default_tag() {
+        let options = options("default-tag = \"section\"");
+        let (output, _) = annotated_with(
+            vec![
+                Event::Start(Tag::Paragraph),
+                text("{:.a}"),
+                Event::End(Tag::Paragraph),
+                Event::Start(Tag::Paragraph),
+                text("{:.b tag=aside}"),
+                Event::End(Tag::Paragraph),
+                Event::Start(Tag::Paragraph),
+                text("{:.c summary=More}"),
+                Event::End(Tag::Paragraph),
+            ],
+            &options,
+        );
+        for tag in &[
+            "<section class=\"a\">",
+            "</section>",
+            "<aside class=\"b\">",
+            "<details class=\"c\">",
+        ] {
+            assert!(output.contains(&html(tag)), "no {} in {:?}", tag, output);
+        }
+
+        let config = toml::from_str("default-tag = \"two words\"").unwrap();
+        assert!(Options::new(Config::from_table(&config).unwrap()).is_err());
+        let config = toml::from_str("default-tag = \"script\"").unwrap();
+        assert!(Options::new(Config::from_table(&config).unwrap()).is_err());
+    }
+
+    #[test]
+    fn only_wraps_blocks_in_block_elements() {
+        for tag in &["script", "style", "iframe", "textarea", "title", "span"] {
+            let annotation = format!("{{:.a tag={}}}", tag);
+            let input = vec![
+                Event::Start(Tag::Paragraph),
+                Event::Text(CowStr::from(annotation.clone())),
+                Event::End(Tag::Paragraph),
+            ];
+            let (output, problems) = annotated(input.clone());
+            assert_eq!(output, input);
+            assert_eq!(
+                problems[0].1.to_string(),
+                format!(
+                    "class annotation `{}` has the tag `{}`, which isn't one of div, section, aside, \
+                     article, figure, details, nav, header, footer, main",
+                    annotation, tag
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn ends_captioned_blocks_with_a_figcaption() {
+        let (output, problems) = annotated_with(
+            vec![
+                Event::Start(Tag::Paragraph),
+                text("{:.figure caption=\"Fig 1\"}"),
+                Event::SoftBreak,
+                text("A diagram."),
+                Event::End(Tag::Paragraph),
+            ],
+            &options("default-tag = \"section\""),
+        );
+        assert!(problems.is_empty());
+        assert_eq!(
+            output,
+            vec![
+                html("<figure class=\"figure\">"),
+                Event::Start(Tag::Paragraph),
+                text("A diagram."),
+                Event::End(Tag::Paragraph),
+                html("<figcaption>Fig 1</figcaption></figure>"),
+                Event::End(Tag::Paragraph),
+            ]
+        );
+    }
+
+    #[test]
+    fn titles_admonitions_as_their_presets_say() {
+        let presets = options(
+            "[admonitions]\nwarning = { title = \"Careful!\" }\nrecipe = { title = \"Try this\", role = \"doc-example\" }\nexercise = {}",
+        );
+        let content = "{:.warning}\nA.\n\n{:.recipe}\nB.\n\n{:.exercise}\nC.\n\n{:.tip}\nD.\n";
+        let titles: Vec<String> = transform(content, &presets)
+            .content
+            .lines()
+            .filter(|line| line.starts_with('<') && !line.starts_with("</"))
+            .map(str::to_string)
+            .collect();
+        assert_eq!(
+            titles,
+            vec![
+                "<div class=\"admonition warning\"><p class=\"admonition-title\">Careful!</p>",
+                "<div class=\"admonition recipe\"><p class=\"admonition-title\">Try this</p>",
+                "<div class=\"admonition exercise\"><p class=\"admonition-title\">Exercise</p>",
+                "<div class=\"admonition tip\"><p class=\"admonition-title\">Tip</p>",
+            ]
+        );
+
+        // A new preset's role is used with aria on, and an explicit title still beats the preset's.
+        let aria = options(
+            "aria = true\n[admonitions]\nrecipe = { title = \"Try this\", role = \"doc-example\" }",
+        );
+        assert!(transform("{:.recipe title=\"Pancakes\"}\nB.\n", &aria)
+            .content
+            .starts_with(
+                "<div class=\"admonition recipe\" role=\"doc-example\" aria-label=\"Pancakes\">\
+             <p class=\"admonition-title\">Pancakes</p>"
+            ));
+    }
+
+    #[test]
+    fn collapses_blocks_with_a_summary_into_details() {
+        let content =
+            "{:.solution summary=\"Show the solution\"}\nHidden.\n\n{:.more tag=details}\nAlso hidden.\n";
+        assert_eq!(
+            transform(content, &Options::default()).content,
+            "<details class=\"solution\"><summary>Show the solution</summary>\n\nHidden.\n\n</details>\n\n\n\
+             <details class=\"more\"><summary>Details</summary>\n\nAlso hidden.\n\n</details>\n"
+        );
+    }
+
+    #[test]
+    fn wraps_github_alerts_and_drops_their_marker() {
+        let alerts = options("github-alerts = true");
+        let rendered = |markdown: &str| {
+            let mut html = String::new();
+            pulldown_cmark::html::push_html(&mut html, markdown_parser(markdown));
+            html
+        };
+        let alert = transform("> [!NOTE]\n> Heads up.\n", &alerts).content;
+        assert_eq!(
+            alert,
+            "<div class=\"alert alert-note\">\n\n > \n > Heads up.\n\n</div>\n"
+        );
+        // No title of its own: the kind is in the class, and the quote follows the wrapper directly.
+        assert_eq!(
+            rendered(&alert),
+            "<div class=\"alert alert-note\">\n<blockquote>\n<p>Heads up.</p>\n</blockquote>\n</div>\n"
+        );
+
+        // Anything else in brackets is an ordinary quote.
+        let unknown = transform("> [!FOO]\n> Not one.\n", &alerts);
+        assert!(unknown.problems.is_empty());
+        assert_eq!(
+            rendered(&unknown.content),
+            "<blockquote>\n<p>[!FOO]\nNot one.</p>\n</blockquote>\n"
+        );
+    }
+
+    #[test]
+    fn labels_admonitions_and_alerts_only_with_aria_on() {
+        let admonition = "{:.warning}\nCareful.\n";
+        assert_eq!(
+            transform(admonition, &options("aria = true\n[admonitions]")).content,
+            "<div class=\"admonition warning\" role=\"alert\" aria-label=\"Warning\">\
+             <p class=\"admonition-title\">Warning</p>\n\nCareful.\n\n</div>\n"
+        );
+        assert_eq!(
+            transform(admonition, &options("[admonitions]")).content,
+            "<div class=\"admonition warning\"><p class=\"admonition-title\">Warning</p>\n\nCareful.\n\n</div>\n"
+        );
+
+        let alert = "> [!TIP]\n> Try this.\n";
+        let with_aria = transform(alert, &options("aria = true\ngithub-alerts = true")).content;
+        assert!(
+            with_aria
+                .starts_with("<div class=\"alert alert-tip\" role=\"note\" aria-label=\"Tip\">\n"),
+            "{}",
+            with_aria
+        );
+        let without = transform(alert, &options("github-alerts = true")).content;
+        assert!(
+            without.starts_with("<div class=\"alert alert-tip\">\n"),
+            "{}",
+            without
+        );
+    }
+
+    #[test]
+    fn recognizes_annotations_the_parser_split_up() {
+        let transformed = transform(
+            "{:.x title=\"Tom &amp; Jerry [1]\"}\nText\n",
+            &Options::default(),
+        );
+        assert!(transformed.problems.is_empty());
+        assert!(transformed
+            .content
+            .contains("<div class=\"classy-title\">Tom &amp; Jerry [1]</div>"));
+
+        let options = options("open-token = \"[[\"\nclose-token = \"]]\"");
+        let transformed = transform("[[.note]]\nText\n\n[[#a #b]]\nNo.\n", &options);
+        assert!(transformed.content.contains("<div class=\"note\">"));
+        assert_eq!(
+            transformed.problems[0].error.to_string(),
+            "class annotation `[[#a #b]]` sets more than one id"
+        );
+    }
+
+    #[test]
+    fn opens_and_closes_templated_classes_with_their_templates() {
+        let options = options(
+            "[templates]\nnote = { open = \"<aside class='{class}'><header>{title}</header>\", \
+             close = \"</aside>\" }",
+        );
+        let (output, _) = annotated_with(
+            vec![
+                Event::Start(Tag::Paragraph),
+                text("{:.small .note title=\"A & B\"}"),
+                Event::End(Tag::Paragraph),
+                Event::Start(Tag::Paragraph),
+                text("{:.other}"),
+                Event::End(Tag::Paragraph),
+            ],
+            &options,
+        );
+        assert_eq!(
+            output[0],
+            html("<aside class='small note'><header>A &amp; B</header>")
+        );
+        assert_eq!(output[3], html("</aside>"));
+        assert!(output.contains(&html("<div class=\"other\">")));
+    }
+
+    #[test]
+    fn keeps_task_lists_working() {
+        let content = "{:.todo}\nTo do:\n\n- [ ] open\n- [x] done\n";
+        let transformed = transform(content, &Options::default()).content;
+        assert!(
+            transformed.contains("* [ ] open\n* [x] done"),
+            "{}",
+            transformed
+        );
+    }
+
+    #[test]
+    fn keeps_the_markdown_extensions_mdbook_renders() {
+        let content =
+            "{:.note}\nSee[^1] ~~this~~.\n\n| a | b |\n|---|---|\n| 1 | 2 |\n\n[^1]: The note.\n";
+        let transformed = transform(content, &Options::default()).content;
+        for kept in &[
+            "See[^1] ~~this~~.",
+            "|a|b|\n|-|-|\n|1|2|",
+            "[^1]: The note.",
+        ] {
+            assert!(
+                transformed.contains(kept),
+                "no {:?} in {:?}",
+                kept,
+                transformed
+            );
+        }
+    }
+
+    #[test]
+    fn writes_settings_back_out() {
+        let written = Config::from_table(
+            &toml::from_str("strict = true\n[aliases]\nwarn = \"a b\"").unwrap(),
+        )
+        .unwrap()
+        .to_toml()
+        .unwrap();
+        assert!(written.contains("[preprocessor.classy]\n"), "{}", written);
+        assert!(written.contains("strict = true\n"), "{}", written);
+        assert!(written.contains("class-prefix = \"\"\n"), "{}", written);
+        assert!(
+            written.contains("[preprocessor.classy.aliases]\nwarn = \"a b\"\n"),
+            "{}",
+            written
+        );
+
+        let read: toml::value::Table = toml::from_str(&written).unwrap();
+        let table = read["preprocessor"]["classy"].as_table().unwrap();
+        assert!(Config::from_table(table).unwrap().strict);
+    }
+
+    #[test]
+    fn skips_draft_chapters_unless_asked() {
+        let book = || {
+            let mut draft = mdbook::book::Chapter::new_draft("Later", vec![]);
+            draft.content = "{:.a}\nFilled in by another preprocessor.\n".to_string();
+            let sub = mdbook::book::Chapter::new(
+                "Sub",
+                "{:.b}\nIn a file.\n".to_string(),
+                "sub.md",
+                vec![],
+            );
+            draft.sub_items.push(BookItem::Chapter(sub));
+            let mut book = Book::new();
+            book.push_item(draft);
+            book
+        };
+        let annotated = |classy: &str| {
+            let config: BookConfig = format!("[preprocessor.classy]\n{}", classy)
+                .parse()
+                .unwrap();
+            let mut book = book();
+            Classy.annotate_book(&config, &mut book).unwrap();
+            let mut wrapped = vec![];
+            book.for_each_mut(|item| {
+                if let BookItem::Chapter(chapter) = item {
+                    wrapped.push((chapter.name.clone(), chapter.content.starts_with("<div")));
+                }
+            });
+            wrapped.sort();
+            wrapped
+        };
+        let wrapped = |later, sub| vec![("Later".to_string(), later), ("Sub".to_string(), sub)];
+        assert_eq!(annotated(""), wrapped(false, true));
+        assert_eq!(annotated("process-drafts = true"), wrapped(true, true));
+    }
+
+    #[test]
+    fn lists_the_classes_used_across_the_book() {
+        let mut book = Book::new();
+        for (path, content) in &[
+            ("one.md", "{:.note}\nA.\n\n{:.note wide}\nB.\n"),
+            ("two.md", "> [!TIP]\n> C.\n\n{:.wide}\nD.\n"),
+        ] {
+            let chapter = mdbook::book::Chapter::new(path, content.to_string(), path, vec![]);
+            book.push_item(BookItem::Chapter(chapter));
+        }
+        let config: BookConfig =
+            "[preprocessor.classy]\ngithub-alerts = true\nclass-prefix = \"x-\""
+                .parse()
+                .unwrap();
+        let classes = Classy.list_classes(&config, &mut book).unwrap();
+        let expected = [
+            ("x-alert", 1),
+            ("x-alert-tip", 1),
+            ("x-note", 2),
+            ("x-wide", 2),
+        ];
+        let expected: Vec<(String, usize)> = expected
+            .iter()
+            .map(|&(class, count)| (class.to_string(), count))
+            .collect();
+        assert_eq!(classes, expected);
+    }
+
+    #[test]
+    fn lists_the_classes_of_chapters_with_problems() {
+        let mut book = Book::new();
+        for (path, content) in &[
+            ("one.md", "{:.note}\nA.\n\n{:#one #two}\nB.\n"),
+            ("two.md", "{:.wide}\nC.\n"),
+        ] {
+            let chapter = mdbook::book::Chapter::new(path, content.to_string(), path, vec![]);
+            book.push_item(BookItem::Chapter(chapter));
+        }
+        let config: BookConfig = "[preprocessor.classy]".parse().unwrap();
+        let classes = Classy.list_classes(&config, &mut book).unwrap();
+        assert_eq!(
+            classes,
+            vec![("note".to_string(), 1), ("wide".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn leaves_excluded_chapters_alone() {
+        let mut book = Book::new();
+        for path in &[
+            "intro.md",
+            "glossary.md",
+            "appendix/generated.md",
+            "appendixes.md",
+        ] {
+            let chapter =
+                mdbook::book::Chapter::new(path, "{:.a}\nText.\n".to_string(), path, vec![]);
+            book.push_item(BookItem::Chapter(chapter));
+        }
+        let config: BookConfig =
+            "[preprocessor.classy]\nexclude = [\"glossary.md\", \"appendix/\"]"
+                .parse()
+                .unwrap();
+        Classy.annotate_book(&config, &mut book).unwrap();
+
+        let mut annotated = vec![];
+        book.for_each_mut(|item| {
+            if let BookItem::Chapter(chapter) = item {
+                annotated.push(chapter.content.starts_with("<div"));
+            }
+        });
+        assert_eq!(annotated, vec![true, false, false, true]);
+    }
+
+    #[test]
+    fn sets_one_inline_style_unless_forbidden() {
+        let input = vec![
+            Event::Start(Tag::Paragraph),
+            text("{:.box style=\"background: yellow; content: '&'\"}"),
+            Event::End(Tag::Paragraph),
+            Event::Start(Tag::Paragraph),
+            text("{:.box style=a style=b}"),
+            Event::End(Tag::Paragraph),
+            Event::Start(Tag::Paragraph),
+            text("{:.box style='x:\"y\"'}"),
+            Event::End(Tag::Paragraph),
+        ];
+        let (output, problems) = annotated(input.clone());
+        assert_eq!(
+            output[0],
+            html("<div class=\"box\" style=\"background: yellow; content: &#39;&amp;&#39;\">")
+        );
+        let problems: Vec<String> = problems.iter().map(|(_, e)| e.to_string()).collect();
+        assert_eq!(
+            problems,
+            vec![
+                "class annotation `{:.box style=a style=b}` sets more than one style",
+                "class annotation `{:.box style='x:\"y\"'}` has `\"`, `<` or `>` in its style `x:\"y\"`",
+            ]
+        );
+
+        let (output, problems) = annotated_with(input, &options("forbid-inline-styles = true"));
+        assert!(!output.iter().any(|event| matches!(event, Event::Html(_))));
+        assert_eq!(
+            problems[0].1.to_string(),
+            "class annotation `{:.box style=\"background: yellow; content: '&'\"}` sets a style, \
+             but inline styles are forbidden in this book"
+        );
+    }
+
+    #[test]
+    fn tells_scripts_how_deep_blocks_are() {
+        let content = "* {:.outer}\n  Outer.\n\n  * {:.inner}\n    Inner.\n\n{:.alone}\nOut.\n";
+        let transformed = transform(content, &options("emit-depth-attr = true")).content;
+        for tag in &[
+            "<div class=\"outer\" data-depth=\"1\">",
+            "<div class=\"inner\" data-depth=\"2\">",
+            "<div class=\"alone\" data-depth=\"1\">",
+        ] {
+            assert!(transformed.contains(tag), "no {} in {}", tag, transformed);
+        }
+        assert!(!transform(content, &Options::default())
+            .content
+            .contains("data-depth"));
+    }
+
+    #[test]
+    fn can_count_lists_for_the_depth() {
+        let options = options("emit-depth-attr = true\ndepth-source = \"list\"");
+        let content =
+            "* {:.item}\n  One.\n\n  * Two.\n\n    {:.inner}\n    Inner.\n\n{:.alone}\nOut.\n";
+        let transformed = transform(content, &options).content;
+        for tag in &[
+            "<div class=\"item\" data-depth=\"1\">",
+            "<div class=\"inner\" data-depth=\"2\">",
+            "<div class=\"alone\" data-depth=\"0\">",
+        ] {
+            assert!(transformed.contains(tag), "no {} in {}", tag, transformed);
+        }
+    }
+
+    #[test]
+    fn wraps_whole_chapters_in_the_chapter_class() {
+        let options = options("chapter-class = \"landing wide\"\nclass-prefix = \"x-\"");
+        let transformed = transform("---\ntitle: T\n---\n\n{:.a}\nIn.\n\nPlain.\n", &options);
+        assert_eq!(
+            transformed.content,
+            "---\ntitle: T\n---\n<div class=\"x-landing x-wide\">\n\n\
+             <div class=\"x-a\">\n\nIn.\n\n</div>\n\n\nPlain.\n\n</div>\n"
+        );
+        let untouched = transform("Plain.\n", &options);
+        assert_eq!(
+            untouched.content,
+            "<div class=\"x-landing x-wide\">\n\nPlain.\n\n</div>\n"
+        );
+    }
+
+    #[test]
+    fn wraps_chapters_in_a_class_for_their_level() {
+        let mut inner =
+            mdbook::book::Chapter::new("Inner", "{:.a}\nIn.\n".to_string(), "b.md", vec![]);
+        inner
+            .sub_items
+            .push(BookItem::Chapter(mdbook::book::Chapter::new(
+                "Innermost",
+                "Deep.\n".to_string(),
+                "c.md",
+                vec![],
+            )));
+        let mut outer = mdbook::book::Chapter::new("Outer", "Top.\n".to_string(), "a.md", vec![]);
+        outer.sub_items.push(BookItem::Chapter(inner));
+        let mut book = Book::new();
+        book.push_item(outer);
+        let config: BookConfig =
+            "[preprocessor.classy]\nchapter-level-class = true\nchapter-class = \"page\""
+                .parse()
+                .unwrap();
+        Classy.annotate_book(&config, &mut book).unwrap();
+
+        let mut contents = vec![];
+        book.for_each_mut(|item| {
+            if let BookItem::Chapter(chapter) = item {
+                contents.push(chapter.content.clone());
+            }
+        });
+        // Sub-chapters are visited before the chapters they are in.
+        contents.reverse();
+        assert_eq!(
+            contents,
+            vec![
+                "<div class=\"page chapter-level-1\">\n\nTop.\n\n</div>\n",
+                "<div class=\"page chapter-level-2\">\n\n<div class=\"a\">\n\nIn.\n\n</div>\n\n</div>\n",
+                "<div class=\"page chapter-level-3\">\n\nDeep.\n\n</div>\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn puts_a_trailing_annotation_on_its_heading() {
+        let content = "## Intro *now* {:.big}\n\nText.\n\n## Intro *now* {:.big #again}\n\n# Plain {not}\n\n# Bad {:#a #b}\n";
+        let transformed = transform(content, &options("class-prefix = \"x-\""));
+        assert!(
+            transformed.content.starts_with(
+                "<h2 class=\"x-big\"><a class=\"header\" href=\"#intro-now\" id=\"intro-now\">\
+                 Intro <em>now</em></a></h2>\n\n\nText.\n\n\
+                 <h2 class=\"x-big\"><a class=\"header\" href=\"#again\" id=\"again\">"
+            ),
+            "{}",
+            transformed.content
+        );
+        assert!(transformed.content.contains("# Plain {not}"));
+        assert!(transformed.content.contains("# Bad {:#a #b}"));
+        assert_eq!(transformed.problems.len(), 1);
+        assert_eq!(transformed.problems[0].line, 9);
+    }
+
+    #[test]
+    fn makes_heading_ids_like_mdbook() {
+        assert_eq!(heading_id("Intro <em>now</em>"), "intro-now");
+        assert_eq!(heading_id("Using <code>a &amp; b</code>!"), "using-a--b");
+        assert_eq!(heading_id(" Why not? "), "why-not");
+    }
+
+    #[test]
+    fn keeps_annotated_heading_ids_clear_of_plain_ones() {
+        let content = "## Intro\n\nText\n\n## Intro {:.big}\n\n## Intro\n\n## Intro {:.big}\n";
+        let transformed = transform(content, &Options::default()).content;
+        // mdbook gives the plain headings `intro` and `intro-1`.
+        for tag in &[
+            "<h2 class=\"big\"><a class=\"header\" href=\"#intro-2\" id=\"intro-2\">",
+            "<h2 class=\"big\"><a class=\"header\" href=\"#intro-3\" id=\"intro-3\">",
+        ] {
+            assert!(transformed.contains(tag), "no {} in {}", tag, transformed);
+        }
+    }
+
+    #[test]
+    fn wraps_fenced_divs() {
+        let options = options("fenced-divs = true");
+        let content =
+            ":::warning\n\nCareful.\n\n::: {:.aside #more}\nNested.\n:::\n\n:::\n\nAfter.\n";
+        let transformed = transform(content, &options);
+        assert!(transformed.problems.is_empty());
+        assert_eq!(
+            transformed.content,
+            "<div class=\"warning\">\n\nCareful.\n\n<div id=\"more\" class=\"aside\">\n\nNested.\n\n</div>\n\n\n\
+             </div>\n\n\nAfter."
+        );
+
+        let transformed = transform(":::\n\n:::note\n\nNever closed.\n", &options);
+        let problems: Vec<(usize, String)> = transformed
+            .problems
+            .iter()
+            .map(|problem| (problem.line, problem.error.to_string()))
+            .collect();
+        assert_eq!(
+            problems,
+            vec![
+                (1, "fence `:::` doesn't close a fenced div".to_string()),
+                (3, "fenced div isn't closed by a `:::` line".to_string()),
+            ]
+        );
+        assert!(transformed.content.ends_with("Never closed.\n\n</div>\n"));
+
+        let untouched = ":::warning\n\nCareful.\n\n:::\n";
+        assert_eq!(transform(untouched, &Options::default()).content, untouched);
+    }
+
+    #[test]
+    fn merges_adjacent_annotated_paragraphs() {
+        let options = options("merge-adjacent = true");
+        let content = "{:.a}\nOne.\n\n{:.b .a #x}\nTwo.\n\nPlain.\n\n{:.c}\nThree.\n\n{:.d tag=aside}\nFour.\n";
+        let transformed = transform(content, &options);
+        assert_eq!(
+            transformed.content,
+            "<div id=\"x\" class=\"a b\">\n\nOne.\n\nTwo.\n\n</div>\n\n\nPlain.\n\n\
+             <div class=\"c\">\n\nThree.\n\n</div>\n\n\n<aside class=\"d\">\n\nFour.\n\n</aside>\n"
+        );
+        assert_eq!(transformed.counts.blocks, 3);
+
+        let separate = transform("{:.a}\nOne.\n\n{:.b}\nTwo.\n", &Options::default());
+        assert_eq!(separate.counts.blocks, 2);
+    }
+
+    #[test]
+    fn leaves_a_chapter_it_cant_write_back_as_it_was() {
+        // A writer that always fails, standing in for the serializer failing.
+        struct Broken;
+        impl fmt::Write for Broken {
+            fn write_str(&mut self, _: &str) -> fmt::Result {
+                Err(fmt::Error)
+            }
+        }
+        impl From<Broken> for String {
+            fn from(_: Broken) -> String {
+                String::new()
+            }
+        }
+        assert!(render(vec![text("Text.")].into_iter(), &mut Broken).is_err());
+
+        let content = "{:.a}\nText.\n";
+        let transformed = annotate_blocks_into(content, &Options::default(), Broken);
+        assert!(matches!(transformed.failure, Some(Failure::Unrendered)));
+        assert_eq!(transformed.content, content);
+        assert!(transformed.problems.is_empty());
+    }
+
+    #[test]
+    fn fails_chapters_with_more_blocks_than_max_blocks_allows() {
+        let options = options("max-blocks = 2");
+        let mut content = "{:.a}\nOne.\n\n{:.b}\nTwo.\n".to_string();
+        assert!(classy(&mut content, "guide.md", 1, &options).is_ok());
+
+        let original = "{:.a}\nOne.\n\n{:.b}\nTwo.\n\n{:.c}\nThree.\n";
+        let mut content = original.to_string();
+        let error = classy(&mut content, "guide.md", 1, &options).unwrap_err();
+        assert!(error.is::<TooManyBlocks>());
+        assert_eq!(
+            error.to_string(),
+            "guide.md: annotates more than 2 blocks, the most `max-blocks` allows"
+        );
+        assert_eq!(content, original);
+    }
+
+    #[test]
+    fn keeps_escaped_annotations_literal() {
+        let content = "\\{:.note}\nShown as it is.\n\n{:.a}\nWrapped.\n";
+        let once = transform(content, &Options::default());
+        assert!(once.problems.is_empty());
+        assert!(once.content.starts_with("\\{:.note}\nShown as it is.\n"));
+        assert!(!once.content.contains("class=\"note\""));
+        assert!(once.content.contains("<div class=\"a\">"));
+        // Still escaped, so running it again changes nothing.
+        assert_eq!(
+            transform(&once.content, &Options::default()).content,
+            once.content
+        );
+
+        let options = options("trailing-markers = true");
+        let content = "## Title \\{:.h}\n\nLast\n\\{:.p}\n\n{:.a}\nWrapped.\n";
+        let once = transform(content, &options).content;
+        assert!(
+            once.starts_with("## Title \\{:.h}\n\nLast\n\\{:.p}\n\n"),
+            "{}",
+            once
+        );
+        assert_eq!(transform(&once, &options).content, once);
+
+        // An escaped one straight after a real one, in a paragraph or a list item.
+        for content in &["{:.a}\n\\{:.e}\nText\n", "- {:.a}\n  \\{:.e}\n  Text\n"] {
+            let once = crate::annotate(content).unwrap();
+            assert!(once.contains("\\{:.e}"), "{}", once);
+            assert!(!once.contains("class=\"e\""), "{}", once);
+            assert_eq!(crate::annotate(&once).unwrap(), once);
+        }
+    }
+
+    #[test]
+    fn leaves_other_escapes_starting_a_paragraph_alone() {
+        let content = "\\*not emphasis*\n\n\\[not a link](x)\n\n{:.a}\nWrapped.\n";
+        assert_eq!(
+            transform(content, &Options::default()).content,
+            "\\*not emphasis\\*\n\n\\[not a link](x)\n\n<div class=\"a\">\n\nWrapped.\n\n</div>\n"
+        );
+    }
+
+    #[test]
+    fn reports_near_miss_annotations() {
+        let content = "{.note}\nOne.\n\n{:note wide}\nTwo.\n\n{not an annotation}\n\nUses {.note} mid-line.\n";
+        let transformed = transform(content, &Options::default());
+        assert!(transformed.problems.is_empty());
+        let near_misses: Vec<(usize, String)> = transformed
+            .near_misses
+            .iter()
+            .map(|near_miss| (near_miss.line, near_miss.error.to_string()))
+            .collect();
+        assert_eq!(
+            near_misses,
+            vec![
+                (
+                    1,
+                    "class annotation `{.note}` is missing its `:`; write `{:.note}`".to_string()
+                ),
+                (
+                    4,
+                    "class annotation `{:note wide}` is missing the `.` before its class; \
+                     write `{:.note wide}`"
+                        .to_string()
+                ),
+            ]
+        );
+        let quiet = transform(content, &options("ignore-near-misses = true"));
+        assert!(quiet.near_misses.is_empty());
+
+        // Only warned about, so they fail neither strict mode nor `annotate`.
+        let mut chapter = content.to_string();
+        let (_, problems) = classy(&mut chapter, "guide.md", 1, &options("strict = true")).unwrap();
+        assert!(problems.is_empty());
+        assert!(crate::annotate(content).is_ok());
+    }
+
+    #[test]
+    fn applies_trailing_annotations_to_their_paragraph() {
+        let options = options("trailing-markers = true");
+        let content = "Over\ntwo lines.\n{:.note #n}\n\nPlain {:.not} trailing.\n\nBad\n{:#a #b}\n";
+        let transformed = transform(content, &options);
+        assert_eq!(
+            transformed.content,
+            "<div id=\"n\" class=\"note\">\n\nOver\ntwo lines.\n\n</div>\n\n\n\
+             Plain {:.not} trailing.\n\nBad\n{:#a #b}"
+        );
+        assert_eq!(transformed.problems.len(), 1);
+        assert_eq!(transformed.problems[0].line, 8);
+
+        let off = transform(content, &Options::default());
+        assert_eq!(off.content, content);
+    }
+
+    #[test]
+    fn gives_blocks_with_an_id_an_anchor() {
+        let options = options("block-anchors = true\nclass-prefix = \"x-\"");
+        let content = "{:#foo.note}\nText.\n\n{:.plain}\nNo id.\n";
+        let transformed = transform(content, &options);
+        assert_eq!(
+            transformed.content,
+            "<div id=\"foo\" class=\"x-note\"><a class=\"x-block-anchor\" href=\"#foo\">#</a>\n\n\
+             Text.\n\n</div>\n\n\n<div class=\"x-plain\">\n\nNo id.\n\n</div>\n"
+        );
+        assert!(!transform(content, &Options::default())
+            .content
+            .contains("anchor"));
+    }
+
+    #[test]
+    fn gives_each_class_once() {
+        let attributes = Attributes::parse(".note .wide note class=\"wide x\"").unwrap();
+        assert_eq!(attributes.classes, vec!["note", "wide", "x"]);
+
+        let options = options("[aliases]\ncallout = \"note box\"\nbox = \"note\"");
+        let transformed = transform("{:.note callout box}\nText.\n", &options);
+        assert!(transformed
+            .content
+            .starts_with("<div class=\"note callout box\">"));
+    }
+
+    #[test]
+    fn gives_every_block_the_default_classes() {
+        let callout = options("default-classes = [\"callout\"]\nclass-prefix = \"c-\"");
+        let content = "{:.note}\nOne.\n\n{:.tip .callout}\nTwo.\n\nPlain.\n";
+        let transformed = transform(content, &callout).content;
+        assert_eq!(
+            transformed,
+            "<div class=\"c-note c-callout\">\n\nOne.\n\n</div>\n\n\n\
+             <div class=\"c-tip c-callout\">\n\nTwo.\n\n</div>\n\n\nPlain."
+        );
+
+        let split = options("default-classes = [\"a b\", \"a\"]");
+        assert_eq!(split.default_classes, vec!["a", "b"]);
+        let invalid = |config: &str| {
+            let config: toml::value::Table = toml::from_str(config).unwrap();
+            Options::new(Config::from_table(&config).unwrap())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            invalid("default-classes = [\"a\", \"<x>\"]"),
+            "preprocessor.classy.default-classes has an invalid class name `<x>`"
+        );
+        assert_eq!(
+            invalid("default-classes = [\"a b\"]\nallowed-classes = [\"a\"]"),
+            "preprocessor.classy.default-classes has `b`, which isn't an allowed class"
+        );
+    }
+
+    #[test]
+    fn summarizes_stats() {
+        let counts = |blocks, depth| Counts {
+            blocks,
+            depth,
+            ..Counts::default()
+        };
+        let book = vec![
+            ("intro.md".to_string(), counts(0, 0)),
+            ("guide.md".to_string(), counts(3, 2)),
+            ("faq.md".to_string(), counts(3, 1)),
+        ];
+        assert_eq!(
+            stats_summary(&book),
+            "classy: 6 block(s) annotated in 2 of 3 chapter(s), nested at most 2 deep; most in guide.md (3)"
+        );
+        assert_eq!(
+            stats_summary(&book[..1]),
+            "classy: 0 block(s) annotated in 0 of 1 chapter(s), nested at most 0 deep"
+        );
+    }
+
+    #[test]
+    fn annotates_in_parallel_as_it_does_in_order() {
+        let annotated = |config: &str| {
+            let mut book = Book::new();
+            for n in 0..20 {
+                let path = format!("{}.md", n);
+                let content = format!("{{:.c{}}}\nText.\n\n{{:#a{} #b}}\nBad.\n", n, n);
+                let chapter = mdbook::book::Chapter::new(&path, content, &path, vec![]);
+                book.push_item(BookItem::Chapter(chapter));
+            }
+            let (problems, _) = Classy
+                .annotate_chapters(&options(config), &mut book)
+                .unwrap();
+            let mut contents = vec![];
+            book.for_each_mut(|item| {
+                if let BookItem::Chapter(chapter) = item {
+                    contents.push(chapter.content.clone());
+                }
+            });
+            (contents, problems)
+        };
+        let (contents, problems) = annotated("parallel = true");
+        assert!(
+            contents[3].starts_with("<div class=\"c3\">"),
+            "{}",
+            contents[3]
+        );
+        assert_eq!(problems.len(), 20);
+        assert_eq!(
+            problems[3],
+            "3.md:4: class annotation `{:#a3 #b}` sets more than one id"
+        );
+        assert_eq!((contents, problems), annotated("parallel = false"));
+    }
+
+    #[test]
+    fn counts_chapters_with_problems_in_the_stats() {
+        let mut book = Book::new();
+        for (path, content) in &[
+            ("a.md", "{:.note}\nA.\n\n{:#one #two}\nB.\n"),
+            ("b.md", "{:.wide}\nC.\n"),
+        ] {
+            let chapter = mdbook::book::Chapter::new(path, content.to_string(), path, vec![]);
+            book.push_item(BookItem::Chapter(chapter));
+        }
+        let (problems, counts) = Classy
+            .annotate_chapters(&Options::default(), &mut book)
+            .unwrap();
+        assert_eq!(
+            problems,
+            vec!["a.md:4: class annotation `{:#one #two}` sets more than one id"]
+        );
+        assert_eq!(
+            stats_summary(&counts),
+            "classy: 2 block(s) annotated in 2 of 2 chapter(s), nested at most 1 deep; most in a.md (1)"
+        );
+    }
+
+    #[test]
+    fn parses_attributes() {
+        let attributes =
+            Attributes::parse(r#"#intro.note small role="alert" data-x='two words'"#).unwrap();
+        assert_eq!(attributes.id.as_deref(), Some("intro"));
+        assert_eq!(attributes.classes, vec!["note", "small"]);
+        assert_eq!(
+            attributes.start_tag(),
+            r#"<div id="intro" class="note small" role="alert" data-x="two words">"#
+        );
+        assert!(Attributes::parse(r#".x title="open"#).is_err());
+        assert!(Attributes::parse(".x =value").is_err());
+    }
+}
@@ -1,142 +1,41 @@
 use clap::{App, Arg, ArgMatches, SubCommand};
-use mdbook::book::{Book, Chapter};
+use log::{error, warn, LevelFilter};
+use mdbook::book::{load_book, Book};
 use mdbook::errors::Error;
-use mdbook::preprocess::{CmdPreprocessor, Preprocessor, PreprocessorContext};
-use pulldown_cmark::{CowStr, Event, Parser, Tag};
-use std::io;
+use mdbook::preprocess::{CmdPreprocessor, Preprocessor};
+use mdbook::Config;
+use mdbook_classy::Classy;
+use semver::{Version, VersionReq};
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
 use std::process;
 
-#[derive(Default)]
-pub struct Classy;
-
-impl Classy {
-    pub fn new() -> Classy {
-        Classy
-    }
-}
-
-impl Preprocessor for Classy {
-    fn name(&self) -> &str {
-        "classy"
-    }
-    fn run(&self, _ctx: &PreprocessorContext, mut book: Book) -> Result<Book, Error> {
-        book.for_each_mut(|book| {
-            if let mdbook::BookItem::Chapter(chapter) = book {
-                if let Err(e) = classy(chapter) {
-                    eprintln!("classy error: {:?}", e);
-                }
-            }
-        });
-        Ok(book)
-    }
-    fn supports_renderer(&self, renderer: &str) -> bool {
-        renderer == "html"
-    }
-}
-
-#[derive(Debug)]
-struct ClassAnnotation {
-    pub class: String,
-    pub index: usize,
-    pub paragraph_start: usize,
-    pub paragraph_end: Option<usize>,
-}
-
-/// This is where the markdown transformation actually happens.
-/// Take paragraphs beginning with `{:.class-name}` and give them special rendering.
-/// Mutation: the payload here is that it edits chapter.content.
-fn classy(chapter: &mut Chapter) -> Result<(), Error> {
-    // 1. Parse the inbound markdown into an Event vector.
-    let incoming_events: Vec<Event> = Parser::new(&chapter.content).collect();
-
-    // 2. Find paragraphs beginning with the class annotator `{:.class-name}` and record their information in
-    // a vector of ClassAnnotation structs.
-    let mut class_annotations: Vec<ClassAnnotation> = vec![];
-    for i in 0..incoming_events.len() {
-        let event = &incoming_events[i];
-        match *event {
-            Event::Text(CowStr::Borrowed(text)) => {
-                if i > 0 {
-                    if let Event::Start(Tag::Paragraph) = incoming_events[i - 1] {
-                        let v: Vec<_> = text.split("").collect();
-                        let len_v = v.len();
-                        if v[..4].join("") == "{:." && v[(len_v - 2)..].join("") == "}" {
-                            let class = v[4..(len_v - 2)].join("");
-                            class_annotations.push(ClassAnnotation {
-                                class,
-                                index: i,
-                                paragraph_start: i - 1,
-                                paragraph_end: None,
-                            })
-                        }
-                    }
-                }
-            }
-            Event::End(Tag::Paragraph) => {
-                let last = class_annotations.last_mut();
-                if let Some(class_command) = last {
-                    if class_command.paragraph_end.is_none() {
-                        class_command.paragraph_end = Some(i);
-                    }
-                }
-            }
-            _ => {}
-        }
-    }
-
-    // 3. Construct a new_events vector with <div class="class-name">\n \n</div> around the annotated paragraphs
-    // (and with the class annotation removed).
-    let mut slices = vec![];
-    let mut last_end = 0;
-    let div_starts: Vec<Event> = class_annotations
-        .iter()
-        .map(|ca| Event::Html(CowStr::from(format!("<div class=\"{}\">", ca.class))))
-        .collect();
-    let div_end: Vec<Event> = vec![Event::Html(CowStr::from("</div>"))];
-    for (i, ca) in class_annotations.iter().enumerate() {
-        // Add unclassed events.
-        slices.push(&incoming_events[last_end..ca.paragraph_start]);
-
-        last_end = ca.paragraph_end.unwrap_or(incoming_events.len() - 1);
-
-        let paragraph = &incoming_events[ca.paragraph_start..(last_end + 1)];
-
-        // Add <div class="class-name">
-        slices.push(&div_starts[i..i + 1]);
-
-        // Add paragraph opener.
-        slices.push(&paragraph[0..1]);
-
-        // Add the rest of the paragraph, skipping the class annotation.
-        slices.push(&paragraph[2..]);
-
-        // Add </div>.
-        slices.push(&div_end[..]);
-    }
-    slices.push(&incoming_events[last_end..]);
-    let new_events = slices.concat();
-
-    // 4. Update chapter.content using markdown generated from the new event vector.
-    let mut buf = String::with_capacity(chapter.content.len() + 128);
-    pulldown_cmark_to_cmark::cmark(new_events.into_iter(), &mut buf, None)
-        .expect("can re-render cmark");
-    chapter.content = buf;
-    Ok(())
-}
-
 /// Housekeeping:
 /// 1. Check compatibility between preprocessor and mdbook
 /// 2. deserialize, run the transformation, and reserialize.
 fn handle_preprocessing(pre: &dyn Preprocessor) -> Result<(), Error> {
-    let (ctx, book) = CmdPreprocessor::parse_input(io::stdin())?;
+    let mut input = String::new();
+    io::stdin().read_to_string(&mut input)?;
+    let not_from_mdbook = |problem: &str| {
+        Error::msg(format!(
+            "{}: this is an mdbook preprocessor, meant to be run by mdbook with the book on stdin. \
+             Add it to a book with `mdbook-classy install`, or see `mdbook-classy --help`",
+            problem
+        ))
+    };
+    if input.trim().is_empty() {
+        return Err(not_from_mdbook("no input"));
+    }
+    let (ctx, book) = CmdPreprocessor::parse_input(input.as_bytes())
+        .map_err(|e| not_from_mdbook(&format!("couldn't read the book from stdin ({})", e)))?;
 
-    if ctx.mdbook_version != mdbook::MDBOOK_VERSION {
-        // We should probably use the `semver` crate to check compatibility
-        // here...
-        eprintln!(
-            "Warning: The {} plugin was built against version {} of mdbook, \
+    if !is_compatible(&ctx.mdbook_version) {
+        warn!(
+            "The {} plugin (version {}) was built against version {} of mdbook, \
              but we're being called from version {}",
             pre.name(),
+            env!("CARGO_PKG_VERSION"),
             mdbook::MDBOOK_VERSION,
             ctx.mdbook_version
         );
@@ -148,10 +47,27 @@ fn handle_preprocessing(pre: &dyn Preprocessor) -> Result<(), Error> {
     Ok(())
 }
 
-/// Check to see if we support the processor (classy only supports html right now)
-fn handle_supports(pre: &dyn Preprocessor, sub_args: &ArgMatches) -> ! {
+/// Whether the calling mdbook can be trusted to speak the protocol of the one we were built against:
+/// the same major version (minor, while still 0.x) and no older than it.
+fn is_compatible(mdbook_version: &str) -> bool {
+    let required = VersionReq::parse(&format!("^{}", mdbook::MDBOOK_VERSION));
+    let running = Version::parse(mdbook_version);
+    match (required, running) {
+        (Ok(required), Ok(running)) => required.matches(&running),
+        _ => mdbook_version == mdbook::MDBOOK_VERSION,
+    }
+}
+
+/// Check to see if we support the renderer: html and epub, unless the book's `supported-renderers` says
+/// otherwise.  mdbook doesn't say which book it's building, so it's taken to be the nearest book.toml at or
+/// above the directory mdbook runs in; with none there, the defaults apply.
+fn handle_supports(pre: &Classy, sub_args: &ArgMatches) -> ! {
     let renderer = sub_args.value_of("renderer").expect("Required argument");
-    let supported = pre.supports_renderer(&renderer);
+    let config = nearest_book_toml().and_then(|book_toml| Config::from_disk(book_toml).ok());
+    let supported = match config {
+        Some(config) => pre.supports_renderer_in(&config, renderer),
+        None => pre.supports_renderer(renderer),
+    };
 
     if supported {
         process::exit(0);
@@ -160,24 +76,183 @@ fn handle_supports(pre: &dyn Preprocessor, sub_args: &ArgMatches) -> ! {
     }
 }
 
+/// The book.toml in the current directory or the closest of its parents that has one.
+fn nearest_book_toml() -> Option<PathBuf> {
+    let cwd = std::env::current_dir().ok()?;
+    cwd.ancestors()
+        .map(|dir| dir.join("book.toml"))
+        .find(|book_toml| book_toml.is_file())
+}
+
+/// Wire the preprocessor into a book by adding a `[preprocessor.classy]` table to its book.toml.
+/// Running it again on a book that already has the table changes nothing.
+fn handle_install(pre: &dyn Preprocessor, sub_args: &ArgMatches) -> Result<(), Error> {
+    let book_dir = Path::new(sub_args.value_of("dir").unwrap_or("."));
+    let book_toml = book_dir.join("book.toml");
+    let config = fs::read_to_string(&book_toml)
+        .map_err(|e| Error::msg(format!("couldn't read {}: {}", book_toml.display(), e)))?;
+    let updated = mdbook_classy::install_table(&config, pre.name())
+        .map_err(|e| Error::msg(format!("couldn't parse {}: {}", book_toml.display(), e)))?;
+
+    let config = match updated {
+        Some(config) => config,
+        None => {
+            println!(
+                "{} already has [preprocessor.{}], nothing to do",
+                book_toml.display(),
+                pre.name()
+            );
+            return Ok(());
+        }
+    };
+    fs::write(&book_toml, config)?;
+    println!(
+        "Added [preprocessor.{}] to {}",
+        pre.name(),
+        book_toml.display()
+    );
+    Ok(())
+}
+
+/// Load the config and chapters of the book in `dir`, for working on it without mdbook.
+fn load_book_in(book_dir: &Path) -> Result<(Config, Book), Error> {
+    let book_toml = book_dir.join("book.toml");
+    let config = Config::from_disk(&book_toml)
+        .map_err(|e| Error::msg(format!("couldn't load {}: {}", book_toml.display(), e)))?;
+    // Looking at a book shouldn't create the chapter files SUMMARY.md links to but that don't exist yet.
+    let mut build = config.build.clone();
+    build.create_missing = false;
+    let src_dir = book_dir.join(&config.book.src);
+    let book = load_book(&src_dir, &build)
+        .map_err(|e| Error::msg(format!("couldn't load {}: {}", src_dir.display(), e)))?;
+    Ok((config, book))
+}
+
+/// Lint a book's annotations without building it: report every problem, and fail if there are any.
+fn handle_check(pre: &Classy, sub_args: &ArgMatches) -> Result<(), Error> {
+    let book_dir = Path::new(sub_args.value_of("dir").unwrap_or("."));
+    let (config, mut book) = load_book_in(book_dir)?;
+
+    pre.check_book(&config, &mut book)?;
+    println!("No annotation problems in {}", book_dir.display());
+    Ok(())
+}
+
+/// Print every class a book's annotations use, one per line and sorted, optionally with how many blocks
+/// have each: for making sure a theme styles them all.
+fn handle_list_classes(pre: &Classy, sub_args: &ArgMatches) -> Result<(), Error> {
+    let book_dir = Path::new(sub_args.value_of("dir").unwrap_or("."));
+    let (config, mut book) = load_book_in(book_dir)?;
+
+    for (class, count) in pre.list_classes(&config, &mut book)? {
+        if sub_args.is_present("counts") {
+            println!("{}\t{}", class, count);
+        } else {
+            println!("{}", class);
+        }
+    }
+    Ok(())
+}
+
+/// Print a book's classy settings, defaults included, to see what the preprocessor will make of them.
+fn handle_config(sub_args: &ArgMatches) -> Result<(), Error> {
+    let book_dir = Path::new(sub_args.value_of("dir").unwrap_or("."));
+    let book_toml = book_dir.join("book.toml");
+    let config = Config::from_disk(&book_toml)
+        .map_err(|e| Error::msg(format!("couldn't load {}: {}", book_toml.display(), e)))?;
+    print!("{}", mdbook_classy::Config::from_book(&config)?.to_toml()?);
+    Ok(())
+}
+
+/// Log warnings and errors only, unless asked for more with `-v` (info) or `-vv` (debug, which includes
+/// per-chapter block counts).  `RUST_LOG` filters, if set, refine that.
+fn init_logging(verbosity: u64) {
+    let level = match verbosity {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        _ => LevelFilter::Debug,
+    };
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(level);
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    builder.init();
+}
+
 fn main() {
     // 1. Define command interface, requiring renderer to be specified.
     let matches = App::new("classy")
+        .version(env!("CARGO_PKG_VERSION"))
         .about("A mdbook preprocessor that recognizes kramdown style paragraph class annotation.")
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .multiple(true)
+                .global(true)
+                .help("Log more: -v for progress, -vv for per-chapter details"),
+        )
         .subcommand(
             SubCommand::with_name("supports")
                 .arg(Arg::with_name("renderer").required(true))
                 .about("Check whether a renderer is supported by this preprocessor"),
         )
+        .subcommand(
+            SubCommand::with_name("install")
+                .arg(Arg::with_name("dir").help("The book's root directory [default: .]"))
+                .about("Add the preprocessor to a book's book.toml"),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .arg(Arg::with_name("dir").help("The book's root directory [default: .]"))
+                .about("Report malformed annotations without building the book"),
+        )
+        .subcommand(
+            SubCommand::with_name("list-classes")
+                .arg(Arg::with_name("dir").help("The book's root directory [default: .]"))
+                .arg(
+                    Arg::with_name("counts")
+                        .long("counts")
+                        .help("Follow each class with how many blocks have it"),
+                )
+                .about("List the classes the book's annotations use"),
+        )
+        .subcommand(
+            SubCommand::with_name("config")
+                .arg(Arg::with_name("dir").help("The book's root directory [default: .]"))
+                .about("Print the book's classy settings, defaults included"),
+        )
         .get_matches();
+    init_logging(matches.occurrences_of("verbose"));
 
     // 2. Instantiate the preprocessor.
     let preprocessor = Classy::new();
 
     if let Some(sub_args) = matches.subcommand_matches("supports") {
         handle_supports(&preprocessor, sub_args);
+    } else if let Some(sub_args) = matches.subcommand_matches("install") {
+        if let Err(e) = handle_install(&preprocessor, sub_args) {
+            error!("{}", e);
+            process::exit(1);
+        }
+    } else if let Some(sub_args) = matches.subcommand_matches("check") {
+        if let Err(e) = handle_check(&preprocessor, sub_args) {
+            error!("{}", e);
+            process::exit(1);
+        }
+    } else if let Some(sub_args) = matches.subcommand_matches("list-classes") {
+        if let Err(e) = handle_list_classes(&preprocessor, sub_args) {
+            error!("{}", e);
+            process::exit(1);
+        }
+    } else if let Some(sub_args) = matches.subcommand_matches("config") {
+        if let Err(e) = handle_config(sub_args) {
+            error!("{}", e);
+            process::exit(1);
+        }
     } else if let Err(e) = handle_preprocessing(&preprocessor) {
-        eprintln!("{}", e);
+        error!("{}", e);
         process::exit(1);
     }
 }
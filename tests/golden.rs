@@ -0,0 +1,88 @@
+//! Runs every `tests/golden/*.in.md` chapter through `annotate` and compares the result with the
+//! matching `*.out.md`.  Set `CLASSY_BLESS=1` to rewrite the expected files from the current output.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn golden_inputs() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let mut inputs: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.to_string_lossy().ends_with(".in.md"))
+        .collect();
+    inputs.sort();
+    assert!(!inputs.is_empty(), "no golden files in {}", dir.display());
+    inputs
+}
+
+#[test]
+fn golden_files() {
+    let bless = std::env::var_os("CLASSY_BLESS").is_some();
+    let mut failures = vec![];
+    for input in golden_inputs() {
+        let expected_path = input.with_file_name(
+            input
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .replace(".in.md", ".out.md"),
+        );
+        let content = fs::read_to_string(&input).unwrap();
+        let actual = mdbook_classy::annotate(&content)
+            .unwrap_or_else(|e| panic!("{}: {}", input.display(), e));
+        if bless {
+            fs::write(&expected_path, &actual).unwrap();
+            continue;
+        }
+        let expected = fs::read_to_string(&expected_path).unwrap_or_default();
+        if actual != expected {
+            failures.push(format!(
+                "{}\n--- expected ---\n{}\n--- actual ---\n{}",
+                input.display(),
+                expected,
+                actual
+            ));
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n\n"));
+}
+
+fn to_html(markdown: &str) -> String {
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, pulldown_cmark::Parser::new(markdown));
+    html
+}
+
+#[test]
+fn annotating_twice_changes_nothing() {
+    for input in golden_inputs() {
+        let content = fs::read_to_string(&input).unwrap();
+        let once = mdbook_classy::annotate(&content).unwrap();
+        let twice = mdbook_classy::annotate(&once).unwrap();
+        assert_eq!(once, twice, "{} is not idempotent", input.display());
+    }
+}
+
+#[test]
+fn annotations_in_blockquotes_stay_inside_the_quote() {
+    let content = "> {:.callout}\n> Quoted.\n>\n> > {:.inner}\n> > Deeper.\n>\n> Still quoted.\n";
+    let annotated = mdbook_classy::annotate(content).unwrap();
+    assert_eq!(
+        to_html(&annotated),
+        "<blockquote>\n<div class=\"callout\">\n<p>Quoted.</p>\n</div>\n\
+         <blockquote>\n<div class=\"inner\">\n<p>Deeper.</p>\n</div>\n</blockquote>\n\
+         <p>Still quoted.</p>\n</blockquote>\n"
+    );
+}
+
+#[test]
+fn malformed_annotations_are_all_reported() {
+    let content = "{:#one #two}\nFirst.\n\n{:.fine}\nSecond.\n\n{:.x title=\"open}\nThird.\n";
+    let error = mdbook_classy::annotate(content).unwrap_err().to_string();
+    assert_eq!(
+        error,
+        "line 1: class annotation `{:#one #two}` sets more than one id\n\
+         line 7: class annotation `{:.x title=\"open}` has an unterminated quote"
+    );
+}